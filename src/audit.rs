@@ -0,0 +1,130 @@
+//! The audit log of the sudo daemon, to which the authentication attempts of
+//! `sudo`, `su` and `passwd` are appended, see the `audit_log` setting of
+//! [`crate::sudoers`].
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::unix_time;
+
+/// Authentication attempts recorded in the audit log.
+pub struct AuditEvent<'a> {
+    /// `sudo`, `su` or `passwd`
+    pub service: &'static str,
+    pub uid: u32,
+    pub target: Option<u32>,
+    pub command: Option<&'a [String]>,
+    pub tty: Option<&'a str>,
+    /// `granted` without password, `denied` by policy, the `success` or
+    /// `failure` of a password check, or `throttled` if the password was not
+    /// checked after too many failures
+    pub outcome: &'static str,
+}
+
+impl AuditEvent<'_> {
+    /// The line recording the event at `time`, in seconds since the Unix
+    /// epoch. Unknown fields are written as `-`.
+    fn line(&self, time: u64) -> String {
+        let target = self.target.map_or("-".to_string(), |uid| uid.to_string());
+        let command = self
+            .command
+            .map_or("-".to_string(), |argv| format!("{:?}", argv.join(" ")));
+        format!(
+            "sudo: audit time={} service={} uid={} target={} command={} tty={} outcome={}\n",
+            time,
+            self.service,
+            self.uid,
+            target,
+            command,
+            self.tty.unwrap_or("-"),
+            self.outcome,
+        )
+    }
+}
+
+pub struct AuditLog {
+    sink: Option<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> AuditLog {
+        if path.is_empty() {
+            return AuditLog { sink: None };
+        }
+
+        let sink = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|err| eprintln!("sudo: failed to open audit log {}: {}", path, err))
+            .ok();
+        AuditLog { sink }
+    }
+
+    pub fn record(&mut self, event: AuditEvent) {
+        let Some(sink) = &mut self.sink else {
+            return;
+        };
+
+        // A single write keeps concurrent records on separate lines
+        if let Err(err) = sink.write_all(event.line(unix_time()).as_bytes()) {
+            eprintln!("sudo: failed to write audit log: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_format() {
+        let command = ["/usr/bin/id".to_string(), "-u".to_string()];
+        let event = AuditEvent {
+            service: "sudo",
+            uid: 1000,
+            target: Some(0),
+            command: Some(&command),
+            tty: Some("/scheme/pty/1"),
+            outcome: "success",
+        };
+        assert_eq!(
+            event.line(1700000000),
+            "sudo: audit time=1700000000 service=sudo uid=1000 target=0 \
+             command=\"/usr/bin/id -u\" tty=/scheme/pty/1 outcome=success\n"
+        );
+    }
+
+    #[test]
+    fn line_format_of_unknown_fields() {
+        let event = AuditEvent {
+            service: "passwd",
+            uid: 1000,
+            target: None,
+            command: None,
+            tty: None,
+            outcome: "throttled",
+        };
+        assert_eq!(
+            event.line(0),
+            "sudo: audit time=0 service=passwd uid=1000 target=- command=- tty=- \
+             outcome=throttled\n"
+        );
+    }
+
+    #[test]
+    fn empty_path_disables_the_log() {
+        let mut log = AuditLog::open("");
+        assert!(log.sink.is_none());
+        log.record(AuditEvent {
+            service: "su",
+            uid: 1000,
+            target: Some(0),
+            command: None,
+            tty: None,
+            outcome: "failure",
+        });
+    }
+}
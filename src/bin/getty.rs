@@ -1,7 +1,6 @@
 #[macro_use]
 extern crate clap;
 
-use core::ptr::slice_from_raw_parts;
 use std::error::Error;
 use std::fs::File;
 use std::io::{self, ErrorKind, Read, Stderr, Write};
@@ -12,10 +11,10 @@ use std::time::{Duration, Instant};
 
 use event::{EventFlags, RawEventQueue};
 use extra::io::fail;
-use libc::{grantpt, ptsname, strlen, unlockpt};
 use libredox::call as redox;
 use libredox::errno::EAGAIN;
 use libredox::flag;
+use userutils::pty::getpty;
 
 const _MAN_PAGE: &'static str = /* @MANSTART{getty} */
     r#"
@@ -114,35 +113,6 @@ pub fn handle(
     process.wait().expect("getty: failed to wait on login");
 }
 
-pub fn getpty(columns: u16, lines: u16) -> (RawFd, String) {
-    let master = redox::open(
-        "/scheme/pty/ptmx",
-        flag::O_CLOEXEC | flag::O_RDWR | flag::O_CREAT | flag::O_NONBLOCK,
-        0,
-    )
-    .expect("getty: failed to create PTY");
-
-    if let Ok(winsize_fd) = redox::dup(master, b"winsize") {
-        let _ = redox::write(
-            winsize_fd,
-            &redox_termios::Winsize {
-                ws_row: lines,
-                ws_col: columns,
-            },
-        );
-        let _ = redox::close(winsize_fd);
-    }
-    let _ = unsafe { grantpt(master as RawFd) };
-    let _ = unsafe { unlockpt(master as RawFd) };
-
-    let name = unsafe { ptsname(master as RawFd) };
-    let count = unsafe { strlen(name) };
-    let buf = unsafe { &*slice_from_raw_parts(name.cast(), count) };
-    (master as RawFd, unsafe {
-        String::from_utf8_unchecked(Vec::from(&buf[..count]))
-    })
-}
-
 // termion cursor_pos prone to error and does not work on nonblocking files
 fn tty_cursor_pos(tty: &mut File) -> Result<(u16, u16), Box<dyn Error>> {
     write!(tty, "\x1B[6n")?;
@@ -204,7 +174,7 @@ fn daemon(tty: &mut File, clear: bool, contain: bool, stderr: &mut Stderr) {
     let (columns, lines) = tty_columns_lines(tty).unwrap_or((DEFAULT_COLS, DEFAULT_LINES));
     let tty_fd = tty.as_raw_fd();
 
-    let (master_fd, pty) = getpty(columns, lines);
+    let (master_fd, pty) = getpty(columns, lines).expect("getty: failed to create PTY");
 
     let mut event_queue = event::RawEventQueue::new().expect("getty: failed to open event queue");
 
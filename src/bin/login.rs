@@ -2,14 +2,22 @@
 extern crate clap;
 
 use libredox::error::Result;
-use std::fs::File;
-use std::io::{self, Write};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Stderr, Stdout, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+use std::process::exit;
 use std::str;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use extra::option::OptionalExt;
-use redox_users::{All, AllUsers, Config, User};
+use redox_users::{All, AllGroups, AllUsers, Config, User};
+use serde::{Deserialize, Serialize};
 use termion::input::TermRead;
-use userutils::spawn_shell;
+use userutils::{AllGroupsExt, spawn_shell};
 
 const _MAN_PAGE: &'static str = /* @MANSTART{login} */
     r#"
@@ -18,21 +26,91 @@ NAME
 
 SYNOPSIS
     login
+    login --check-config
 
 DESCRIPTION
     The login utility logs users (and pseudo-users) into the computer system.
 
+    Users without a password are logged in at once. Every other name is asked
+    for a password, whether a user has it or not, and a failed attempt is
+    reported as "Login incorrect" after the same time, so that the users of
+    the system cannot be told from the prompt.
+
+    The shell of a user only gets the schemes given for them in
+    /etc/login_schemes.toml in its namespace:
+
+        [default]
+        deny = ["irq", "serio"]
+
+        [group_schemes.audio]
+        allow = ["audio"]
+
+        [group_schemes.guests]
+        schemes = ["file", "pty", "null", "rand"]
+
+        [user_schemes.root]
+        allow = ["irq", "serio"]
+
+    The entries applying to a user are merged from the least to the most
+    specific one: [default], then the entries of the groups of the user, then
+    the entry of the user. An entry with schemes replaces the schemes given
+    so far, the lists of several groups adding up. allow adds schemes and
+    deny removes schemes, deny taking precedence over allow within the same
+    level. Without a [default] list of schemes, every scheme used by the
+    system is given.
+
+    If the file cannot be read or is invalid, the error is reported and users
+    only get the schemes file, pty, pipe, null, zero, rand, event, time and
+    sudo, which is enough to fix the file.
+
+    After a failed attempt, login waits before asking for the next user and
+    exits after too many failed attempts, for getty to start it again. The
+    failed attempts of every account are counted in
+    /var/lib/login/failures.toml, and an account with too many failures in a
+    row is locked for a while: its password is refused even if correct. root
+    is never locked. These limits are read from /etc/login.toml:
+
+        # Seconds to wait after a failed attempt
+        fail_delay = 3
+        # Failed attempts after which login exits, 0 for no limit
+        max_attempts = 5
+        # Failures in a row after which an account is locked, 0 to never
+        # lock accounts
+        lockout_failures = 10
+        # Seconds an account stays locked, failures older than this being
+        # forgotten
+        lockout_duration = 600
+
 OPTIONS
 
     -h --help
         Display help info and exit.
 
+    --check-config
+        Check /etc/login_schemes.toml and print the schemes every user gets.
+        Groups and users of the file which do not exist are reported. The
+        exit status is non-zero if the file is invalid.
+
 AUTHOR
     Written by Jeremy Soller, Jose Narvaez.
 "#; /* @MANEND */
 
 const ISSUE_FILE: &'static str = "/etc/issue";
 const MOTD_FILE: &'static str = "/etc/motd";
+const LOGIN_SCHEMES_FILE: &'static str = "/etc/login_schemes.toml";
+const LOGIN_FILE: &'static str = "/etc/login.toml";
+const FAILURES_FILE: &'static str = "/var/lib/login/failures.toml";
+/// Delay of a failed `User::verify_passwd`, also spent by `dummy_verify`.
+const AUTH_DELAY: Duration = Duration::from_secs(3);
+/// Hash of a password of no account, hashed like `User::set_passwd` does, see
+/// `dummy_verify`.
+const DUMMY_HASH: &'static str = "$argon2i$v=19$m=4096,t=3,p=1$bG9naW4tZHVtbXktc2FsdA$txq/h+pmH8ERGiyP2+W8ObEuadfzlh6K5HpZIyaeljI";
+
+/// Schemes given to users if `LOGIN_SCHEMES_FILE` is invalid, rather than
+/// every scheme. Enough to fix the file.
+const FALLBACK_SCHEMES: [&'static str; 9] = [
+    "file", "pty", "pipe", "null", "zero", "rand", "event", "time", "sudo",
+];
 
 // TODO: Move to redox_users once the definition solidifies.
 const DEFAULT_SCHEMES: [&'static str; 26] = [
@@ -74,10 +152,7 @@ pub fn apply_login_schemes(
     user: &User<redox_users::auth::Full>,
     default_schemes: &[&str],
 ) -> Result<libredox::Fd> {
-    let schemes = match load_config_schemes(user) {
-        Some(s) => s,
-        _ => default_schemes.iter().map(|s| s.to_string()).collect(),
-    };
+    let schemes = load_config_schemes(user, default_schemes);
 
     let mut names: Vec<ioslice::IoSlice> = Vec::with_capacity(schemes.len());
     for scheme in schemes.iter() {
@@ -90,48 +165,419 @@ pub fn apply_login_schemes(
     Ok(before_ns_fd)
 }
 
-fn load_config_schemes(user: &User<redox_users::auth::Full>) -> Option<Vec<String>> {
-    use serde::{Deserialize, Serialize};
-    use std::collections::BTreeMap;
-    use std::fs;
+/// Why `LOGIN_SCHEMES_FILE` cannot be used.
+#[derive(Debug)]
+enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "{}", err),
+            ConfigError::Parse(err) => write!(f, "{}", err),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Schemes of the `[default]`, a `[group_schemes.NAME]` or a
+/// `[user_schemes.NAME]` entry of `LOGIN_SCHEMES_FILE`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SchemeEntry {
+    /// Replaces the schemes given by less specific entries
+    schemes: Option<Vec<String>>,
+    /// Added to the schemes
+    allow: Vec<String>,
+    /// Removed from the schemes, even if allowed by another entry of the same
+    /// level
+    deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoginConfig {
+    default: SchemeEntry,
+    group_schemes: BTreeMap<String, SchemeEntry>,
+    user_schemes: BTreeMap<String, SchemeEntry>,
+}
+
+impl LoginConfig {
+    /// Read `LOGIN_SCHEMES_FILE`, `None` if it does not exist.
+    fn load() -> std::result::Result<Option<LoginConfig>, ConfigError> {
+        let config_str = match fs::read_to_string(LOGIN_SCHEMES_FILE) {
+            Ok(config_str) => config_str,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(ConfigError::Io(err)),
+        };
+        let config: LoginConfig = toml::from_str(&config_str).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    /// Check the scheme names, which the syntax does not restrict.
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        let entries = std::iter::once(("[default]".to_string(), &self.default))
+            .chain(
+                self.group_schemes
+                    .iter()
+                    .map(|(name, entry)| (format!("[group_schemes.{}]", name), entry)),
+            )
+            .chain(
+                self.user_schemes
+                    .iter()
+                    .map(|(name, entry)| (format!("[user_schemes.{}]", name), entry)),
+            );
+        for (name, entry) in entries {
+            let schemes = entry.schemes.iter().flatten();
+            for scheme in schemes.chain(&entry.allow).chain(&entry.deny) {
+                if scheme.is_empty() || scheme.contains(['/', ':']) {
+                    return Err(ConfigError::Invalid(format!(
+                        "{}: invalid scheme name {:?}",
+                        name, scheme
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The schemes of `user`, a member of `groups`. The entries applying to
+    /// the user are merged from the least to the most specific one: first
+    /// `[default]`, starting from `default_schemes`, then the entries of the
+    /// groups, then the entry of the user.
+    fn schemes_for(&self, user: &str, groups: &[String], default_schemes: &[&str]) -> Vec<String> {
+        let levels: [Vec<&SchemeEntry>; 3] = [
+            vec![&self.default],
+            groups
+                .iter()
+                .filter_map(|group| self.group_schemes.get(group))
+                .collect(),
+            self.user_schemes.get(user).into_iter().collect(),
+        ];
+
+        let mut schemes: Vec<String> = default_schemes.iter().map(|s| s.to_string()).collect();
+        for entries in levels {
+            // The lists of several groups add up
+            let replacements: Vec<&Vec<String>> = entries
+                .iter()
+                .filter_map(|entry| entry.schemes.as_ref())
+                .collect();
+            if !replacements.is_empty() {
+                schemes = replacements.into_iter().flatten().cloned().collect();
+            }
+            for entry in &entries {
+                schemes.extend(entry.allow.iter().cloned());
+            }
+            schemes.retain(|scheme| !entries.iter().any(|entry| entry.deny.contains(scheme)));
+
+            let mut seen = BTreeSet::new();
+            schemes.retain(|scheme| seen.insert(scheme.clone()));
+        }
+        schemes
+    }
+}
+
+/// The schemes of `user`, or `FALLBACK_SCHEMES` if they cannot be known for
+/// sure.
+fn load_config_schemes(
+    user: &User<redox_users::auth::Full>,
+    default_schemes: &[&str],
+) -> Vec<String> {
+    let fallback = || FALLBACK_SCHEMES.iter().map(|s| s.to_string()).collect();
 
-    const LOGIN_SCHEMES_FILE: &'static str = "/etc/login_schemes.toml";
+    let config = match LoginConfig::load() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(err) => {
+            eprintln!("login: invalid {}: {}", LOGIN_SCHEMES_FILE, err);
+            eprintln!(
+                "login: only giving the schemes {}",
+                FALLBACK_SCHEMES.join(", ")
+            );
+            return fallback();
+        }
+    };
+    // Entries of groups may deny schemes
+    let groups = match AllGroups::new(Config::default()) {
+        Ok(groups) => groups.member_groups(&user.user, user.gid),
+        Err(err) => {
+            eprintln!("login: failed to read the groups of {}: {}", user.user, err);
+            eprintln!(
+                "login: only giving the schemes {}",
+                FALLBACK_SCHEMES.join(", ")
+            );
+            return fallback();
+        }
+    };
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    struct UserSchemeConfig {
-        pub schemes: Vec<String>,
+    config.schemes_for(&user.user, &groups, default_schemes)
+}
+
+/// Check `LOGIN_SCHEMES_FILE` and print the schemes of every user, see
+/// `login --check-config`.
+fn check_config() -> ! {
+    let config = match LoginConfig::load() {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!(
+                "{} does not exist, using the default schemes",
+                LOGIN_SCHEMES_FILE
+            );
+            LoginConfig::default()
+        }
+        Err(err) => {
+            eprintln!("login: invalid {}: {}", LOGIN_SCHEMES_FILE, err);
+            exit(1);
+        }
+    };
+
+    let users = AllUsers::basic(Config::default()).unwrap_or_exit(1);
+    let groups = AllGroups::new(Config::default()).unwrap_or_exit(1);
+
+    for name in config.group_schemes.keys() {
+        if groups.get_by_name(name).is_none() {
+            eprintln!("login: warning: group {} does not exist", name);
+        }
+    }
+    for name in config.user_schemes.keys() {
+        if users.get_by_name(name).is_none() {
+            eprintln!("login: warning: user {} does not exist", name);
+        }
+    }
+
+    for user in users.iter() {
+        let member_groups = groups.member_groups(&user.user, user.gid);
+        let schemes = config.schemes_for(&user.user, &member_groups, &DEFAULT_SCHEMES);
+        println!("{}: {}", user.user, schemes.join(", "));
     }
+    exit(0);
+}
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    struct LoginConfig {
-        #[serde(rename = "user_schemes")]
-        pub user_schemes: BTreeMap<String, UserSchemeConfig>,
+/// Set up the session of an authenticated user, whatever the way they
+/// authenticated: show the message of the day, restrict the namespace to the
+/// schemes of the user and run their shell. The namespace of login is
+/// restored once the shell exits.
+fn run_session(user: &User<redox_users::auth::Full>, stdout: &mut Stdout, stderr: &mut Stderr) {
+    if let Ok(mut motd) = File::open(MOTD_FILE) {
+        io::copy(&mut motd, stdout).r#try(stderr);
+        stdout.flush().r#try(stderr);
     }
 
-    let config_str = fs::read_to_string(LOGIN_SCHEMES_FILE).ok()?;
-    let config: LoginConfig = toml::from_str(&config_str).ok()?;
+    let before_ns_fd = apply_login_schemes(user, &DEFAULT_SCHEMES).unwrap_or_exit(1);
+
+    // The shell must not inherit the unrestricted namespace
+    let _ = libredox::call::fcntl(
+        before_ns_fd.raw(),
+        syscall::F_SETFD,
+        libredox::protocol::O_CLOEXEC,
+    );
+    spawn_shell(user).unwrap_or_exit(1);
+    let _ = libredox::call::fcntl(before_ns_fd.raw(), syscall::F_SETFD, 0);
+    let _ = libredox::call::close(libredox::call::setns(before_ns_fd.into_raw()).unwrap_or_exit(1));
+}
+
+/// Limits on failed attempts, read from `LOGIN_FILE`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoginSettings {
+    /// Seconds to wait after a failed attempt.
+    fail_delay: u64,
+    /// Failed attempts after which login exits, 0 for no limit.
+    max_attempts: u32,
+    /// Failures in a row after which an account is locked, 0 to never lock
+    /// accounts.
+    lockout_failures: u32,
+    /// Seconds an account stays locked.
+    lockout_duration: u64,
+}
 
-    config
-        .user_schemes
-        .get(&user.user)
-        .map(|cfg| cfg.schemes.clone())
+impl Default for LoginSettings {
+    fn default() -> LoginSettings {
+        LoginSettings {
+            fail_delay: 3,
+            max_attempts: 5,
+            lockout_failures: 10,
+            lockout_duration: 600,
+        }
+    }
+}
+
+impl LoginSettings {
+    /// Read `LOGIN_FILE`, the defaults being used if it is missing or invalid.
+    fn load() -> LoginSettings {
+        let settings_str = match fs::read_to_string(LOGIN_FILE) {
+            Ok(settings_str) => settings_str,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return LoginSettings::default(),
+            Err(err) => {
+                eprintln!("login: failed to read {}: {}", LOGIN_FILE, err);
+                return LoginSettings::default();
+            }
+        };
+        toml::from_str(&settings_str).unwrap_or_else(|err| {
+            eprintln!("login: invalid {}: {}", LOGIN_FILE, err);
+            LoginSettings::default()
+        })
+    }
+}
+
+/// Failures in a row of an account.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FailureCount {
+    count: u32,
+    /// Seconds since the Unix epoch of the last failure.
+    last: u64,
+}
+
+/// The failure counts of `FAILURES_FILE`, shared by every login.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Failures {
+    users: BTreeMap<String, FailureCount>,
+}
+
+impl Failures {
+    fn load() -> Failures {
+        // Only root can write the file, an unreadable one is started over
+        fs::read_to_string(FAILURES_FILE)
+            .ok()
+            .and_then(|failures_str| toml::from_str(&failures_str).ok())
+            .unwrap_or_default()
+    }
+
+    /// Change the failure counts with `update`, which returns whether they
+    /// have to be written. `FAILURES_FILE` stays locked meanwhile, so logins
+    /// on other consoles wait for the change instead of overwriting it.
+    fn update(update: impl FnOnce(&mut Failures) -> bool) {
+        let result = (|| -> io::Result<()> {
+            if let Some(dir) = Path::new(FAILURES_FILE).parent() {
+                DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+            }
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .mode(0o600)
+                .custom_flags(syscall::O_EXLOCK as i32)
+                .open(FAILURES_FILE)?;
+            let mut failures_str = String::new();
+            file.read_to_string(&mut failures_str)?;
+            // An unreadable file is started over, like in `load`
+            let mut failures: Failures = toml::from_str(&failures_str).unwrap_or_default();
+            if !update(&mut failures) {
+                return Ok(());
+            }
+
+            let failures_str = toml::to_string(&failures)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(failures_str.as_bytes())
+        })();
+        if let Err(err) = result {
+            eprintln!("login: failed to write {}: {}", FAILURES_FILE, err);
+        }
+    }
+
+    /// The failures of `user` within the lockout duration.
+    fn count(&self, user: &str, settings: &LoginSettings) -> u32 {
+        match self.users.get(user) {
+            Some(failures)
+                if unix_time() < failures.last.saturating_add(settings.lockout_duration) =>
+            {
+                failures.count
+            }
+            _ => 0,
+        }
+    }
+
+    fn is_locked(&self, user: &User<redox_users::auth::Full>, settings: &LoginSettings) -> bool {
+        // root is never locked, so that the console stays usable
+        user.uid != 0
+            && settings.lockout_failures != 0
+            && self.count(&user.user, settings) >= settings.lockout_failures
+    }
+}
+
+/// Count a failed attempt to log into `user`.
+fn record_failure(user: &str, settings: &LoginSettings) {
+    Failures::update(|failures| {
+        let count = failures.count(user, settings).saturating_add(1);
+        failures.users.insert(
+            user.to_string(),
+            FailureCount {
+                count,
+                last: unix_time(),
+            },
+        );
+        true
+    });
+}
+
+/// Forget the failures of `user` after a successful login.
+fn clear_failures(user: &str) {
+    Failures::update(|failures| failures.users.remove(user).is_some());
+}
+
+/// Spend the time of a failed `User::verify_passwd` for an unknown or locked
+/// account, whose password is not checked.
+fn dummy_verify(password: &str) {
+    let _ = argon2::verify_encoded(DUMMY_HASH, password.as_bytes());
+    thread::sleep(AUTH_DELAY);
+}
+
+/// Report a failed attempt after the fail delay, exiting after the maximum
+/// number of attempts.
+fn login_failed(
+    settings: &LoginSettings,
+    attempts: &mut u32,
+    stdout: &mut Stdout,
+    stderr: &mut Stderr,
+) {
+    thread::sleep(Duration::from_secs(settings.fail_delay));
+    stdout.write(b"\nLogin incorrect\n").r#try(stderr);
+    stdout.write(b"\n").r#try(stderr);
+    stdout.flush().r#try(stderr);
+
+    *attempts += 1;
+    if settings.max_attempts != 0 && *attempts >= settings.max_attempts {
+        eprintln!("login: too many failed attempts");
+        exit(1);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
 }
 
 pub fn main() {
     let mut stdout = io::stdout();
     let mut stderr = io::stderr();
 
-    let _args = clap_app!(login =>
+    let args = clap_app!(login =>
         (author: "Jeremy Soller, Jose Narvaez")
         (about: "Login as a user")
+        (@arg CHECK_CONFIG: --("check-config")
+            "Check /etc/login_schemes.toml and print the schemes of every user")
     )
     .get_matches();
 
+    if args.is_present("CHECK_CONFIG") {
+        check_config();
+    }
+
     if let Ok(mut issue) = File::open(ISSUE_FILE) {
         io::copy(&mut issue, &mut stdout).r#try(&mut stderr);
         stdout.flush().r#try(&mut stderr);
     }
 
+    let settings = LoginSettings::load();
+    let mut attempts = 0;
+
     loop {
         let user = liner::Context::new()
             .read_line(
@@ -144,61 +590,156 @@ pub fn main() {
         if !user.is_empty() {
             let stdin = io::stdin();
             let mut stdin = stdin.lock();
-            let sys_users = AllUsers::authenticator(Config::default()).unwrap_or_exit(1);
-
-            match sys_users.get_by_name(user) {
-                None => {
-                    stdout.write(b"\nLogin incorrect\n").r#try(&mut stderr);
-                    stdout.write(b"\n").r#try(&mut stderr);
-                    stdout.flush().r#try(&mut stderr);
-                    continue;
-                }
-                Some(user) => {
-                    if user.is_passwd_blank() {
-                        if let Ok(mut motd) = File::open(MOTD_FILE) {
-                            io::copy(&mut motd, &mut stdout).r#try(&mut stderr);
-                            stdout.flush().r#try(&mut stderr);
-                        }
+            let sys_users =
+                AllUsers::authenticator(Config::default().auth_delay(AUTH_DELAY)).unwrap_or_exit(1);
 
-                        let before_ns_fd =
-                            apply_login_schemes(user, &DEFAULT_SCHEMES).unwrap_or_exit(1);
-
-                        let _ = libredox::call::fcntl(
-                            before_ns_fd.raw(),
-                            syscall::F_SETFD,
-                            libredox::protocol::O_CLOEXEC,
-                        );
-                        spawn_shell(user).unwrap_or_exit(1);
-                        let _ = libredox::call::fcntl(before_ns_fd.raw(), syscall::F_SETFD, 0);
-                        let _ = libredox::call::close(
-                            libredox::call::setns(before_ns_fd.into_raw()).unwrap_or_exit(1),
-                        );
-                        break;
-                    }
+            let blank = sys_users
+                .get_by_name(&user)
+                .filter(|account| account.is_passwd_blank());
+            if let Some(account) = blank {
+                run_session(account, &mut stdout, &mut stderr);
+                break;
+            }
 
-                    stdout
-                        .write_all(b"\x1B[1mpassword:\x1B[0m ")
-                        .r#try(&mut stderr);
-                    stdout.flush().r#try(&mut stderr);
-                    if let Some(password) = stdin.read_passwd(&mut stdout).r#try(&mut stderr) {
-                        stdout.write(b"\n").r#try(&mut stderr);
-                        stdout.flush().r#try(&mut stderr);
-
-                        if user.verify_passwd(&password) {
-                            if let Ok(mut motd) = File::open(MOTD_FILE) {
-                                io::copy(&mut motd, &mut stdout).r#try(&mut stderr);
-                                stdout.flush().r#try(&mut stderr);
-                            }
-
-                            spawn_shell(user).unwrap_or_exit(1);
+            // Unknown users are asked for a password too
+            stdout
+                .write_all(b"\x1B[1mpassword:\x1B[0m ")
+                .r#try(&mut stderr);
+            stdout.flush().r#try(&mut stderr);
+            if let Some(password) = stdin.read_passwd(&mut stdout).r#try(&mut stderr) {
+                stdout.write(b"\n").r#try(&mut stderr);
+                stdout.flush().r#try(&mut stderr);
+
+                // The password of a locked account is not even checked
+                let failures = Failures::load();
+                let account = sys_users
+                    .get_by_name(&user)
+                    .filter(|account| !failures.is_locked(account, &settings));
+                match account {
+                    Some(account) => {
+                        if account.verify_passwd(&password) {
+                            clear_failures(&account.user);
+                            run_session(account, &mut stdout, &mut stderr);
                             break;
                         }
+                        record_failure(&account.user, &settings);
                     }
+                    None => dummy_verify(&password),
                 }
             }
+            drop(sys_users); // Unlock /etc/passwd during the delay
+
+            login_failed(&settings, &mut attempts, &mut stdout, &mut stderr);
         } else {
             stdout.write(b"\n").r#try(&mut stderr);
             stdout.flush().r#try(&mut stderr);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> LoginConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn schemes_replace_less_specific_entries() {
+        let config = config(
+            r#"
+            [default]
+            schemes = ["file", "rand"]
+            allow = ["null"]
+
+            [group_schemes.staff]
+            schemes = ["file", "pty"]
+
+            [user_schemes.alice]
+            schemes = ["tcp"]
+            "#,
+        );
+        let defaults = ["debug"];
+        assert_eq!(
+            config.schemes_for("bob", &[], &defaults),
+            ["file", "rand", "null"]
+        );
+        assert_eq!(
+            config.schemes_for("bob", &groups(&["staff"]), &defaults),
+            ["file", "pty"]
+        );
+        assert_eq!(
+            config.schemes_for("alice", &groups(&["staff"]), &defaults),
+            ["tcp"]
+        );
+        assert_eq!(
+            LoginConfig::default().schemes_for("bob", &[], &defaults),
+            ["debug"]
+        );
+    }
+
+    #[test]
+    fn group_lists_add_up() {
+        let config = config(
+            r#"
+            [group_schemes.staff]
+            schemes = ["file", "pty"]
+            allow = ["null"]
+
+            [group_schemes.net]
+            schemes = ["tcp", "file"]
+            allow = ["udp"]
+            "#,
+        );
+        let schemes = config.schemes_for("bob", &groups(&["staff", "net", "other"]), &["debug"]);
+        assert_eq!(schemes, ["file", "pty", "tcp", "null", "udp"]);
+    }
+
+    #[test]
+    fn deny_beats_allow_within_a_level() {
+        let config = config(
+            r#"
+            [default]
+            allow = ["tcp"]
+
+            [group_schemes.staff]
+            allow = ["udp"]
+
+            [group_schemes.guests]
+            deny = ["udp", "file"]
+
+            [user_schemes.alice]
+            allow = ["file"]
+            "#,
+        );
+        let groups = groups(&["staff", "guests"]);
+        assert_eq!(
+            config.schemes_for("bob", &groups, &["file", "pty"]),
+            ["pty", "tcp"]
+        );
+        // A more specific level allows it again
+        assert_eq!(
+            config.schemes_for("alice", &groups, &["file", "pty"]),
+            ["pty", "tcp", "file"]
+        );
+    }
+
+    #[test]
+    fn invalid_scheme_names_are_rejected() {
+        assert!(config("[default]\nallow = [\"file\"]").validate().is_ok());
+        for name in ["", "file:", "/scheme/file"] {
+            let entry = SchemeEntry {
+                deny: vec![name.to_string()],
+                ..SchemeEntry::default()
+            };
+            let mut config = LoginConfig::default();
+            config.user_schemes.insert("alice".to_string(), entry);
+            assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        }
+    }
+}
@@ -6,10 +6,11 @@ use std::io::Write;
 use std::process::exit;
 
 use extra::option::OptionalExt;
-use libredox::flag::O_CLOEXEC;
-use libredox::errno::EPERM;
+use libredox::errno::{EAGAIN, ENOLCK, EPERM, ESRCH};
 use redox_users::{All, AllUsers, Config, get_uid};
 use termion::input::TermRead;
+use userutils::open_sudo_service;
+use userutils::throttle::{retry_delay, wait_to_retry};
 
 const _MAN_PAGE: &'static str = /* @MANSTART{passwd} */
     r#"
@@ -119,19 +120,26 @@ fn main() {
     stdout.write_all(b"current password: ").r#try(&mut stderr);
     stdout.flush().r#try(&mut stderr);
 
-    let file = libredox::call::open("/scheme/sudo/passwd", O_CLOEXEC, 0).unwrap();
+    let file = open_sudo_service("passwd").unwrap_or_else(|err| {
+        eprintln!("passwd: failed to connect to the sudo daemon: {}", err);
+        exit(1);
+    });
 
     if let Some(password) = stdin.read_passwd(&mut stdout).r#try(&mut stderr) {
         stdout.write(b"\n").r#try(&mut stderr);
         stdout.flush().r#try(&mut stderr);
 
-        match libredox::call::write(file, password.as_bytes()) {
-            Ok(_) => {}
-            Err(err) if err.errno() == EPERM => {
-                eprintln!("passwd: incorrect current password");
-                exit(1);
+        loop {
+            match libredox::call::write(file, password.as_bytes()) {
+                Ok(_) => break,
+                Err(err) if err.errno() == EPERM => {
+                    eprintln!("passwd: incorrect current password");
+                    exit(1);
+                }
+                // The password was not checked yet, it is sent again
+                Err(err) if err.errno() == EAGAIN => wait_to_retry("passwd", retry_delay(file)),
+                Err(err) => daemon_failed(err),
             }
-            Err(err) => panic!("{err}"),
         }
     } else {
         eprintln!("passwd: incorrect current password");
@@ -146,8 +154,17 @@ fn main() {
             eprintln!("passwd: invalid new password");
             exit(1);
         }
-        Err(err) => panic!("{err}"),
+        Err(err) => daemon_failed(err),
+    }
+}
+
+fn daemon_failed(err: libredox::error::Error) -> ! {
+    match err.errno() {
+        ENOLCK => eprintln!("passwd: the user database is busy, try again later"),
+        ESRCH => eprintln!("passwd: you do not exist"),
+        _ => eprintln!("passwd: {}", err),
     }
+    exit(1);
 }
 
 fn find_user<'a, T: Default>(
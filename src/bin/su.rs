@@ -6,11 +6,11 @@ use std::process::exit;
 use std::str;
 
 use extra::option::OptionalExt;
-use libredox::flag::O_CLOEXEC;
 use redox_users::{All, AllUsers, Config, get_uid};
-use syscall::EPERM;
+use syscall::{EAGAIN, ENOLCK, EPERM};
 use termion::input::TermRead;
-use userutils::spawn_shell;
+use userutils::{open_sudo_service, spawn_shell};
+use userutils::throttle::{retry_delay, wait_to_retry};
 
 const _MAN_PAGE: &'static str = /* @MANSTART{su} */
     r#"
@@ -61,7 +61,10 @@ pub fn main() {
         writeln!(stdout).unwrap_or_exit(1);
         exit(spawn_shell(user).unwrap_or_exit(1));
     } else {
-        let file = libredox::call::open("/scheme/sudo/su", O_CLOEXEC, 0).unwrap();
+        let file = open_sudo_service("su").unwrap_or_else(|err| {
+            eprintln!("su: failed to connect to the sudo daemon: {}", err);
+            exit(1);
+        });
 
         write!(stdout, "password: ").unwrap_or_exit(1);
         stdout.flush().unwrap_or_exit(1);
@@ -72,13 +75,25 @@ pub fn main() {
             .r#try(&mut stderr)
             .unwrap_or(String::new());
 
-        match libredox::call::write(file, password.as_bytes()) {
-            Ok(_) => exit(spawn_shell(user).unwrap_or_exit(1)),
-            Err(err) if err.errno() == EPERM => {
-                writeln!(stderr, "su: authentication failed").unwrap_or_exit(1);
-                exit(1);
+        loop {
+            match libredox::call::write(file, password.as_bytes()) {
+                Ok(_) => exit(spawn_shell(user).unwrap_or_exit(1)),
+                Err(err) if err.errno() == EPERM => {
+                    writeln!(stderr, "su: authentication failed").unwrap_or_exit(1);
+                    exit(1);
+                }
+                // The password was not checked yet, it is sent again
+                Err(err) if err.errno() == EAGAIN => wait_to_retry("su", retry_delay(file)),
+                Err(err) if err.errno() == ENOLCK => {
+                    writeln!(stderr, "su: the user database is busy, try again later")
+                        .unwrap_or_exit(1);
+                    exit(1);
+                }
+                Err(err) => {
+                    writeln!(stderr, "su: {}", err).unwrap_or_exit(1);
+                    exit(1);
+                }
             }
-            Err(err) => panic!("{err}"),
         }
     }
 }
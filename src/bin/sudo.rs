@@ -1,9 +1,19 @@
+#[macro_use]
+extern crate clap;
+
 use std::collections::HashMap;
 use std::env;
-use std::io::{self, Write};
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::process::CommandExt;
-use std::process::{exit, Command};
+use std::path::{Path, PathBuf};
+use std::process::{self, exit, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use extra::option::OptionalExt;
 use libredox::flag::O_CLOEXEC;
@@ -13,29 +23,290 @@ use redox_scheme::scheme::{register_sync_scheme, SchemeState, SchemeSync};
 use redox_scheme::{
     CallerCtx, OpenResult, RequestKind, Response, SendFdRequest, SignalBehavior, Socket,
 };
-use redox_users::{get_uid, All, AllGroups, AllUsers, Config};
+use redox_users::{auth, get_uid, All, AllGroups, AllUsers, Config, User};
+use serde::{Deserialize, Serialize};
 use syscall::error::*;
 use syscall::flag::*;
 use syscall::schemev2::NewFdFlags;
 use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use userutils::audit::{AuditEvent, AuditLog};
+use userutils::cache::{Account, Accounts, Cache};
+use userutils::iolog::{self, Info};
+use userutils::recording::Recording;
+use userutils::sudoers::{Defaults, ResolveError, Rule, Sudoers, ANY_DIR, SUDOEDIT, SUDOERS_FILE};
+use userutils::throttle::{auth_config, wait_to_retry, Throttle};
+use userutils::unix_time;
 
 const MAX_ATTEMPTS: u16 = 3;
+
+/// Prompt for passwords unless changed with `-p`, see `expand_prompt`.
+const DEFAULT_PROMPT: &str = "[sudo] password for %u: ";
+/// Exit status of `sudo -n` when a password would be needed. Like
+/// `TIMEOUT_STATUS`, it is kept apart from the statuses commands commonly
+/// exit with.
+const PASSWORD_REQUIRED_STATUS: i32 = 125;
+
+/// Scheme under which commands reach the namespace of the user who ran
+/// sudo, as `/scheme/user/SCHEME/PATH`.
+const USER_NS_SCHEME: &str = "user";
+
+/// Exit status of sudo when a command is killed after its timeout, like the
+/// one of timeout(1).
+const TIMEOUT_STATUS: i32 = 124;
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Signals sudo passes on to the command run by the daemon.
+const FORWARDED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+
+/// Editor used if neither `SUDO_EDITOR`, `VISUAL` nor `EDITOR` is set.
+const DEFAULT_EDITOR: &str = "vi";
+
+/// First file descriptor of the caller that commands do not inherit, unless
+/// changed with `--close-from`.
+const CLOSE_FROM: RawFd = 3;
+
 const _MAN_PAGE: &'static str = /* @MANSTART{sudo} */
     r#"
 NAME
     sudo - execute a command as another user
 
 SYNOPSIS
-    sudo command
+    sudo [ -k ] [ -n | -S ] [ -p prompt ] [ -E ] [ -b ] [ -C num ] [ -D dir ]
+         [ -u user ] [ -g group ] command [ args... ]
+    sudo [ -k ] [ -E ] [ -u user ] [ -g group ] -s [ command [ args... ] ]
+    sudo [ -k ] [ -u user ] [ -g group ] -i [ command [ args... ] ]
+    sudo [ -u user ] [ -g group ] -e file...
+    sudoedit [ -u user ] [ -g group ] file...
+    sudo [ -u user ] [ -g group ] -l [ command [ args... ] ]
+    sudo [ -v | -k | -K ]
     sudo [ -h | --help ]
 
 DESCRIPTION
     The sudo utility allows a permitted user to execute a command as the
     superuser or another user, as specified by the security policy.
 
+    After a successful authentication, sudo does not ask for a password in
+    the same login session for a few minutes (see timestamp_timeout below).
+    The session is the one the sudo daemon finds for the calling process,
+    not the terminal named by TTY, which the caller may change.
+
+    Commands are started by the sudo daemon rather than by sudo itself,
+    which hands its standard input, output and error over to the daemon and
+    waits for the command to exit. The hangup, interrupt, quit and terminate
+    signals sudo receives are passed on to the process group of the command.
+    The command runs in the current directory of sudo, or in / if the caller
+    may not search it.
+
+    As a child of the daemon, the command is not part of the session of
+    sudo and has no controlling terminal. It reaches the terminal of the
+    caller through its standard input, output and error and through TTY, so
+    programs opening the controlling terminal of their session, like /dev/tty
+    on other systems, do not find one.
+
+    Commands given by name are searched in secure_path, not in the PATH of
+    the caller. Paths relative to the current directory, like ./script, are
+    refused unless allowed by allow_relative_paths. The policy is checked
+    against the absolute path of the executable.
+
+    Every failed authentication of a user through sudo, su or passwd doubles
+    the time the user has to wait before the next attempt, starting at one
+    second. After 8 failures in a row the user is locked out for 15 minutes.
+    A successful authentication, or 15 minutes without failure, resets
+    this. A password typed too early is checked once the time is up, which
+    sudo, su and passwd wait for.
+
+OPTIONS
+    -h, --help
+        Display this help and exit.
+
+    -u, --user USER
+        Run the command as USER instead of root.
+
+    -g, --group GROUP
+        Run the command with GROUP as primary group instead of the primary
+        group of the target user.
+
+    -s, --shell
+        Run the shell of the target user, or the command through this shell
+        with -c. Every character of the arguments except letters, digits, _,
+        - and $ is escaped with a backslash, so they are passed on as given
+        while variables are still expanded. The policy has to allow running
+        the shell.
+
+    -i, --login
+        Like -s, but run the shell as a login shell in the home directory of
+        the target user. The environment is reset, keeping only TERM and TTY
+        and setting PATH to secure_path.
+
+    -E, --preserve-env, --preserve-env=LIST
+        Keep the environment of the caller, or only the variables of the
+        comma separated LIST in addition to those kept by the policy. This is
+        only allowed by rules with setenv = true.
+
+    -D, --chdir DIR
+        Run the command in DIR. This is only allowed by rules with
+        chdir = "*".
+
+    -b, --background
+        Run the command in the background once authenticated, in a new
+        session without a controlling terminal and with its standard input
+        read from /scheme/null. sudo exits at once. Sessions which are
+        recorded cannot be run in the background.
+
+    -C, --close-from NUM
+        Do not pass the file descriptors of the caller from NUM on to the
+        command. NUM is at least 3, which is the default, so only the
+        standard input, output and error are inherited unless a larger NUM is
+        given. It is at most the number of files a process may have open.
+        File descriptors which are not open are replaced by /scheme/null.
+
+    -e, --edit
+        Edit the given files as the target user, which sudoedit runs sudo
+        for. The files are copied to temporary files owned by
+        the caller, which are opened with the editor named by SUDO_EDITOR,
+        VISUAL or EDITOR (vi by default). The editor runs with the privileges
+        of the caller. Once it exits successfully, the changed files are
+        written back. The policy has to allow the command "sudoedit FILE".
+        Only the superuser may create files this way. Files are refused if a
+        directory leading to them is a symbolic link or may be written by
+        the caller.
+
+    -l, --list
+        List the rules of the security policy applying to the caller. With a
+        command, print the command line that would be run if the caller may
+        run it as the target user, and exit with a non-zero status otherwise.
+        No password is asked for.
+
+    -n, --non-interactive
+        Never ask for a password. If one is needed, print "sudo: a password
+        is required" to the standard error and exit with status 125 instead.
+        Scripts can also check beforehand with sudo -n -v, which runs nothing
+        and exits with status 125 only if a password would be needed, 1 if no
+        rule applies to the caller and 0 otherwise. Commands of rules with
+        nopasswd = true never need a password, nor does sudo -v if every rule
+        applying to the caller has nopasswd = true.
+
+    -S, --stdin
+        Read the password from a line of the standard input instead of the
+        terminal, and write the prompt to the standard error.
+
+    -p, --prompt PROMPT
+        Ask for the password with PROMPT instead of "[sudo] password for %u: ".
+        %u is replaced by the name of the caller, %U by the one of the target
+        user, %h by the host name and %% by %.
+
+    -v, --validate
+        Authenticate if needed and extend the cached credentials without
+        running a command.
+
+    -k, --reset-timestamp
+        Invalidate the cached credentials for the current session. When
+        combined with a command, a password is asked for this command.
+
+    -K, --remove-timestamp
+        Remove the cached credentials for all sessions.
+
+FILES
+    /etc/sudoers.toml
+        The security policy. Each [[rule]] lists the users and groups it
+        applies to, the users they may run commands as (runas, root by
+        default) and the commands they may run:
+
+            [defaults]
+            timestamp_timeout = 5
+            audit_log = "/scheme/log"
+            env_reset = true
+            env_keep = ["TERM", "TTY", "LANG", "LC_*"]
+            secure_path = "/usr/bin"
+
+            [[rule]]
+            groups = ["sudo"]
+            commands = ["ALL"]
+
+            [[rule]]
+            users = ["alice"]
+            runas = ["root", "www"]
+            runas_groups = ["www"]
+            commands = ["/usr/bin/netctl restart", "sudoedit /etc/hosts"]
+            nopasswd = true
+            setenv = true
+
+            [[rule]]
+            groups = ["staff"]
+            commands = ["/usr/bin/chown", "/usr/bin/chmod"]
+            schemes = ["file", "pty"]
+
+        runas_groups lists the groups that may be selected with -g. A rule
+        with nopasswd = true does not ask for a password, one with
+        setenv = true allows -E. The first matching rule applies. A sudoedit
+        command has to list the absolute paths of the files it allows to
+        edit.
+
+        If a rule has a list of schemes, its commands only get these schemes
+        in their namespace instead of every scheme. This grants for example
+        root access to files without access to hardware.
+
+        Otherwise the commands get every scheme of the sudo daemon, and the
+        namespace of the caller under the user scheme. That namespace may be
+        restricted (see login_schemes.toml) or hold schemes of the session
+        of the caller, which commands reach as /scheme/user/SCHEME/PATH.
+
+        A rule may also set a timeout in seconds after which its commands are
+        killed, the directory they run in with chdir ("*" letting the caller
+        choose it with -D) and their file mode creation mask with umask. A
+        command with a timeout runs in a process group of its own, which is
+        killed as a whole, including the processes the command started:
+
+            [[rule]]
+            users = ["backup"]
+            commands = ["/usr/bin/backup"]
+            timeout = 3600
+            chdir = "/"
+            umask = 0o077
+
+        Without this file, members of the sudo group may run any command.
+
+        The daemon reloads this file, /etc/passwd and /etc/group when they are
+        modified. A policy which fails to load is refused and the last one
+        loaded stays in effect. Writing to /scheme/sudo/reload as the
+        superuser reloads them at once, the write failing if the policy is
+        invalid:
+
+            echo > /scheme/sudo/reload
+
+        timestamp_timeout is the number of minutes for which credentials are
+        cached, 0 disabling the cache.
+
+        audit_log is the file every authentication attempt of sudo, su and
+        passwd is appended to, one line per attempt. It is read when the
+        daemon starts, an empty string disabling the audit log.
+
+        Commands are run with a sanitized environment. If env_reset is true
+        (the default), only the variables of env_keep are kept, otherwise all
+        variables except those of env_delete are kept. Names ending with *
+        match any variable starting with the rest of the name. PATH is set to
+        secure_path (/usr/bin by default) unless it is empty, in which case
+        commands have to be given by path. The variables
+        USER, UID, HOME and GROUPS describe the target user.
+
+        Setting allow_relative_paths = true allows commands given as a path
+        relative to the current directory.
+
+        The terminal sessions of the commands of a rule with log_io = true are
+        recorded, those of every command if log_io = true is set in
+        [defaults]. The command then runs on a new pseudo terminal, and what
+        is typed on and written to it is saved with its timing in a directory
+        of iolog_dir (/var/log/sudo-io by default). sudoreplay(8) plays the
+        sessions back.
+
 EXIT STATUS
     Upon successful execution of a command, the exit status from sudo will
-    be the exit status of the program that was executed. In case of error
+    be the exit status of the program that was executed. If the command was
+    killed because its timeout expired, the exit status is 124. With -b,
+    the exit status is 0 once the command is started in the background. If
+    a password is needed with -n, the exit status is 125. In case of error
     the exit status will be >0.
 
 AUTHOR
@@ -47,132 +318,1413 @@ fn main() {
         daemon_main();
     }
 
-    let mut args = env::args().skip(1);
-    let cmd = args.next().unwrap_or_else(|| {
-        eprintln!("sudo: no command provided");
-        exit(1);
-    });
+    let args = clap_app!(sudo =>
+        (author: "Jeremy Soller, Jose Narvaez, bjorn3")
+        (about: "Execute a command as another user")
+        (@setting TrailingVarArg)
+        (@arg USER: -u --user +takes_value "Run the command as USER instead of root")
+        (@arg GROUP: -g --group +takes_value "Run the command with GROUP as primary group")
+        (@arg VALIDATE: -v --validate conflicts_with[COMMAND]
+            "Update the cached credentials without running a command")
+        (@arg RESET_TIMESTAMP: -k --("reset-timestamp")
+            "Invalidate the cached credentials for this session")
+        (@arg REMOVE_TIMESTAMP: -K --("remove-timestamp")
+            conflicts_with[COMMAND VALIDATE RESET_TIMESTAMP SHELL LOGIN]
+            "Remove the cached credentials for all sessions")
+        (@arg SHELL: -s --shell conflicts_with[VALIDATE]
+            "Run the shell of the target user, or the command through it")
+        (@arg LOGIN: -i --login conflicts_with[VALIDATE SHELL]
+            "Run the shell of the target user as a login shell")
+        (@arg PRESERVE_ENV: -E --("preserve-env") +takes_value min_values(0) +require_equals
+            +use_delimiter conflicts_with[VALIDATE LOGIN]
+            "Keep the environment, or only the listed variables")
+        (@arg EDIT: -e --edit conflicts_with[VALIDATE SHELL LOGIN PRESERVE_ENV]
+            "Edit the files given as COMMAND instead of running a command")
+        (@arg LIST: -l --list conflicts_with[VALIDATE SHELL LOGIN PRESERVE_ENV EDIT]
+            "List the commands the user may run, or check whether COMMAND may be run")
+        (@arg CHDIR: -D --chdir +takes_value conflicts_with[VALIDATE EDIT]
+            "Run the command in DIR, if the policy allows it")
+        (@arg BACKGROUND: -b --background conflicts_with[VALIDATE EDIT LIST]
+            "Run the command in the background")
+        (@arg NON_INTERACTIVE: -n --("non-interactive")
+            "Exit with status 125 instead of asking for a password")
+        (@arg STDIN: -S --stdin conflicts_with[NON_INTERACTIVE]
+            "Read the password from the standard input")
+        (@arg PROMPT: -p --prompt +takes_value conflicts_with[NON_INTERACTIVE]
+            "Ask for the password with PROMPT")
+        (@arg CLOSE_FROM: -C --("close-from") +takes_value conflicts_with[VALIDATE EDIT LIST]
+            "Do not pass file descriptors from NUM on to the command")
+        (@arg COMMAND: +multiple "The command to run, followed by its arguments")
+    )
+    .get_matches();
+
+    let tty = env::var("TTY").ok();
+
+    let close_from = match args.value_of("CLOSE_FROM") {
+        Some(num) => match num.parse::<RawFd>() {
+            Ok(fd) if (CLOSE_FROM..=open_max()).contains(&fd) => fd,
+            _ => {
+                eprintln!(
+                    "sudo: --close-from needs a number from {} to {}",
+                    CLOSE_FROM,
+                    open_max()
+                );
+                exit(1);
+            }
+        },
+        None => CLOSE_FROM,
+    };
+
+    if args.is_present("REMOVE_TIMESTAMP") {
+        invalidate_credentials(true);
+        exit(0);
+    }
+
+    if args.is_present("RESET_TIMESTAMP") {
+        invalidate_credentials(false);
+        if !args.is_present("COMMAND") && !args.is_present("SHELL") && !args.is_present("LOGIN") {
+            exit(0);
+        }
+    }
 
     let users = AllUsers::basic(Config::default()).unwrap_or_exit(1);
     let uid = get_uid().unwrap_or_exit(1);
     let user = users.get_by_id(uid).unwrap_or_exit(1);
 
+    let target_name = args.value_of("USER").unwrap_or("root");
+    let target = users.get_by_name(target_name).unwrap_or_else(|| {
+        eprintln!("sudo: unknown user {}", target_name);
+        exit(1);
+    });
+    let target_gid = match args.value_of("GROUP") {
+        Some(name) => {
+            let groups = AllGroups::new(Config::default()).unwrap_or_exit(1);
+            let group = groups.get_by_name(name).unwrap_or_else(|| {
+                eprintln!("sudo: unknown group {}", name);
+                exit(1);
+            });
+            group.gid
+        }
+        None => target.gid,
+    };
+
+    let cwd = env::current_dir()
+        .ok()
+        .and_then(|cwd| cwd.into_os_string().into_string().ok())
+        .unwrap_or_default();
+    let chdir = args.value_of("CHDIR").map(|dir| absolute_path(&cwd, dir));
+
+    // The executable is resolved by whoever checks the policy, see
+    // `Defaults::resolve_command`
+    let login = args.is_present("LOGIN");
+    let background = args.is_present("BACKGROUND");
+    let edit = args.is_present("EDIT");
+    let command_args = args.values_of("COMMAND").into_iter().flatten();
+    let argv: Option<Vec<String>> = if edit {
+        // Files are checked against `sudoedit FILE` commands of the policy
+        let files: Vec<String> = command_args.map(|file| absolute_path(&cwd, file)).collect();
+        (!files.is_empty()).then(|| std::iter::once(SUDOEDIT.to_string()).chain(files).collect())
+    } else if args.is_present("SHELL") || login {
+        // The shell runs the command line, like for `$SHELL -c`
+        let mut argv = vec![target.shell.clone()];
+        let command_line = command_args.map(shell_quote).collect::<Vec<_>>().join(" ");
+        if !command_line.is_empty() {
+            argv.push("-c".to_string());
+            argv.push(command_line);
+        }
+        Some(argv)
+    } else {
+        let argv: Vec<String> = command_args.map(String::from).collect();
+        (!argv.is_empty()).then_some(argv)
+    };
+    if args.is_present("LIST") {
+        list_privileges(target, args.value_of("GROUP"), argv, cwd);
+    }
+
+    if argv.is_none() && !args.is_present("VALIDATE") {
+        eprintln!("sudo: no command provided");
+        exit(1);
+    }
+    let preserve_env: Option<Vec<String>> = args.is_present("PRESERVE_ENV").then(|| {
+        args.values_of("PRESERVE_ENV")
+            .into_iter()
+            .flatten()
+            .map(String::from)
+            .collect()
+    });
+
     if uid == 0 {
         // We are root already. No need to elevate privileges
-        run_command_as_root(&cmd, &args.collect());
+        let Some(mut argv) = argv else {
+            exit(0);
+        };
+        if edit {
+            let target_ids = Target {
+                uid: target.uid as u32,
+                gid: target_gid as u32,
+            };
+            let files = argv[1..]
+                .iter()
+                .map(|path| match open_for_edit(path, target_ids) {
+                    Ok(file) => (path.clone(), file),
+                    Err(err) => {
+                        eprintln!("sudo: {}: {}", path, err);
+                        exit(1);
+                    }
+                })
+                .collect();
+            edit_files(files);
+        }
+        let defaults = load_defaults();
+        argv[0] = match defaults.resolve_command(&argv[0], &cwd) {
+            Ok(path) => path,
+            Err(err) => resolve_failed(&argv[0], err),
+        };
+        let env = command_env(&defaults, caller_env(), preserve_env.as_deref(), login);
+        let log = defaults.log_io.then(|| {
+            let info = Info {
+                time: unix_time(),
+                uid: 0,
+                target: target.uid as u32,
+                command: argv.clone(),
+            };
+            iolog::create(&defaults.iolog_dir, &info).unwrap_or_else(|err| {
+                eprintln!("sudo: failed to record the session: {}", err);
+                exit(1);
+            })
+        });
+        let restrictions = Restrictions {
+            chdir,
+            ..Restrictions::default()
+        };
+        let options = RunOptions {
+            login,
+            background,
+            close_from,
+            terminal_size: termion::terminal_size().ok(),
+        };
+        let account = Account {
+            name: target.user.clone(),
+            uid: target.uid,
+            gid: target.gid,
+            groups: Vec::new(),
+            home: target.home.clone(),
+            shell: target.shell.clone(),
+        };
+        run_command(
+            &argv,
+            &account,
+            target_gid,
+            env,
+            &options,
+            &restrictions,
+            log,
+        )
     }
 
-    let file = libredox::Fd::open("/scheme/sudo", libredox::flag::O_CLOEXEC, 0).unwrap();
+    let file = libredox::Fd::open("/scheme/sudo", libredox::flag::O_CLOEXEC, 0)
+        .unwrap_or_else(|err| daemon_failed(err));
 
-    let mut attempts = 0;
+    let request = Request {
+        user: target.user.clone(),
+        group: args.value_of("GROUP").map(String::from),
+        command: argv.clone().unwrap_or_default(),
+        validate: argv.is_none(),
+        edit,
+        preserve_env: preserve_env.clone(),
+        cwd,
+        chdir,
+        tty,
+        env: caller_env(),
+        options: RunOptions {
+            login,
+            background,
+            close_from,
+            terminal_size: termion::terminal_size().ok(),
+        },
+    };
+    match file.write(toml::to_string(&request).unwrap().as_bytes()) {
+        Ok(_) => {}
+        Err(err) if err.errno() == EACCES => {
+            match &argv {
+                Some(argv) => {
+                    // Name the resolved executable if the daemon got that far
+                    let reply = read_reply(&file);
+                    let path = reply
+                        .as_ref()
+                        .and_then(|reply| reply.command.first())
+                        .unwrap_or(&argv[0]);
+                    eprintln!(
+                        "sudo: {} is not allowed to run {} as {}",
+                        user.user, path, target.user
+                    )
+                }
+                None => eprintln!("sudo: {} may not run sudo", user.user),
+            }
+            exit(1);
+        }
+        Err(err) if err.errno() == ENOENT && argv.is_some() => {
+            resolve_failed(&request.command[0], ResolveError::NotFound)
+        }
+        Err(err) if err.errno() == EINVAL && argv.is_some() => {
+            resolve_failed(&request.command[0], ResolveError::Relative)
+        }
+        Err(err) if err.errno() == EPERM => {
+            let options: Vec<&str> = [
+                request.preserve_env.as_ref().map(|_| "--preserve-env"),
+                request.chdir.as_ref().map(|_| "--chdir"),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            eprintln!(
+                "sudo: {} is not allowed to use {} for this command",
+                user.user,
+                options.join(" or ")
+            );
+            exit(1);
+        }
+        Err(err) => daemon_failed(err),
+    }
 
-    loop {
-        print!("[sudo] password for {}: ", user.user);
-        let _ = io::stdout().flush();
+    let reply = read_reply(&file).unwrap_or_else(|| {
+        eprintln!("sudo: invalid reply from the sudo daemon");
+        exit(1);
+    });
+
+    if reply.password && args.is_present("NON_INTERACTIVE") {
+        eprintln!("sudo: a password is required");
+        exit(PASSWORD_REQUIRED_STATUS);
+    }
 
-        match io::stdin().read_passwd(&mut io::stdout()).unwrap() {
-            Some(password) => {
+    let stdin_password = args.is_present("STDIN");
+    let prompt = expand_prompt(
+        args.value_of("PROMPT").unwrap_or(DEFAULT_PROMPT),
+        &user.user,
+        &target.user,
+    );
+    let mut attempts = 0;
+
+    if reply.password {
+        'prompt: loop {
+            let password = if stdin_password {
+                eprint!("{}", prompt);
+                read_stdin_password()
+            } else {
+                print!("{}", prompt);
+                let _ = io::stdout().flush();
+                let password = io::stdin().read_passwd(&mut io::stdout()).unwrap();
                 println!();
+                password
+            };
 
+            let Some(password) = password else {
+                exit(1);
+            };
+            loop {
                 match file.write(password.as_bytes()) {
-                    Ok(_) => break,
+                    Ok(_) => break 'prompt,
                     Err(err) if err.errno() == EPERM => {
                         attempts += 1;
-                        eprintln!(
-                            "sudo: incorrect password or not in sudo group ({}/{})",
-                            attempts, MAX_ATTEMPTS,
-                        );
+                        eprintln!("sudo: incorrect password ({}/{})", attempts, MAX_ATTEMPTS);
                         if attempts >= MAX_ATTEMPTS {
                             exit(1);
                         }
+                        continue 'prompt;
                     }
-                    Err(err) => panic!("{err}"),
+                    Err(err) if err.errno() == EAGAIN => {
+                        // The password was not checked yet, it is sent again
+                        let retry_after = read_reply(&file).map_or(0, |reply| reply.retry_after);
+                        wait_to_retry("sudo", Duration::from_millis(retry_after));
+                    }
+                    Err(err) => daemon_failed(err),
                 }
             }
-            None => {
-                println!();
-                exit(1);
+        }
+    }
+
+    if argv.is_none() {
+        // Only the cached credentials had to be updated
+        exit(0);
+    }
+    let argv = reply.command;
+
+    if edit {
+        // The daemon opens the files for the target user, the editor runs
+        // without elevated privileges
+        let files = argv[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let fd = file
+                    .openat(&i.to_string(), O_CLOEXEC, 0)
+                    .unwrap_or_else(|err| {
+                        eprintln!("sudo: {}: {}", path, err);
+                        exit(1);
+                    });
+                (path.clone(), unsafe {
+                    File::from_raw_fd(fd.into_raw() as RawFd)
+                })
+            })
+            .collect();
+        edit_files(files);
+    }
+
+    if background && reply.restrictions.log_io {
+        eprintln!("sudo: recorded sessions cannot run in the background");
+        exit(1);
+    }
+
+    // The daemon runs the command itself, with the file descriptors it
+    // inherits handed over in order. Unless restricted to some schemes, it
+    // also gets the namespace of the caller, see `USER_NS_SCHEME`.
+    for fd in 0..close_from {
+        send_fd(&file, inherited_fd(fd));
+    }
+    if reply.restrictions.schemes.is_none() {
+        let ns = libredox::call::getns()
+            .and_then(|ns| libredox::call::dup(ns, b""))
+            .unwrap_or_else(|err| daemon_failed(err));
+        send_fd(&file, ns);
+    }
+
+    // Keys are passed on to the pseudo terminal of a recorded session as they
+    // are typed, it echoes them
+    let raw = (reply.restrictions.log_io && termion::is_tty(&io::stdin()))
+        .then(|| io::stdout().into_raw_mode().ok())
+        .flatten();
+
+    forward_signals(&file);
+    let status = match file.openat("run", O_CLOEXEC, 0) {
+        Ok(status) => status,
+        Err(err) => {
+            drop(raw);
+            eprintln!("sudo: failed to execute {}: {}", argv[0], err);
+            exit(1);
+        }
+    };
+    if background {
+        exit(0);
+    }
+
+    // Written by the daemon once the command exited, see `wait_command`
+    let mut status = unsafe { File::from_raw_fd(status.into_raw() as RawFd) };
+    let mut text = String::new();
+    let _ = status.read_to_string(&mut text);
+    drop(raw);
+    exit(text.trim().parse().unwrap_or(1));
+}
+
+/// A copy of the file descriptor `fd` of the caller to hand over to the
+/// daemon, which passes it on to the command. `/scheme/null` stands in for
+/// file descriptors which are not open.
+fn inherited_fd(fd: RawFd) -> usize {
+    libredox::call::dup(fd as usize, b"")
+        .or_else(|_| libredox::call::open("/scheme/null", libredox::flag::O_RDWR, 0))
+        .unwrap_or_else(|err| daemon_failed(err))
+}
+
+/// Move `fd` to the daemon, which appends it to those the command inherits.
+fn send_fd(file: &libredox::Fd, fd: usize) {
+    file.call_wo(&fd.to_ne_bytes(), syscall::CallFlags::FD, &[])
+        .unwrap_or_else(|err| daemon_failed(err));
+}
+
+/// Handle of the request whose command `forward_signal` signals.
+static COMMAND_HANDLE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let _ = libredox::call::write(COMMAND_HANDLE.load(Ordering::Relaxed), &[signal as u8]);
+}
+
+/// Pass the signals sent to sudo, like the interrupt of its terminal, on to
+/// the command run by the daemon through `file`, see `Handle::Running`.
+fn forward_signals(file: &libredox::Fd) {
+    COMMAND_HANDLE.store(file.raw(), Ordering::Relaxed);
+    for signal in FORWARDED_SIGNALS {
+        unsafe {
+            libc::signal(
+                signal,
+                forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+    }
+}
+
+/// How the caller asked for a command to be run.
+#[derive(Serialize, Deserialize)]
+struct RunOptions {
+    /// Run the command like a login shell, see `sudo -i`
+    login: bool,
+    /// Run the command in a session of its own, see `sudo -b`
+    background: bool,
+    /// First file descriptor of the caller not inherited by the command
+    close_from: RawFd,
+    /// Columns and lines of the terminal of the caller, the size of the
+    /// pseudo terminal of a recorded session
+    terminal_size: Option<(u16, u16)>,
+}
+
+impl Default for RunOptions {
+    fn default() -> RunOptions {
+        RunOptions {
+            login: false,
+            background: false,
+            close_from: CLOSE_FROM,
+            terminal_size: None,
+        }
+    }
+}
+
+/// Sent by the client as the first write on a `/scheme/sudo` handle.
+#[derive(Serialize, Deserialize)]
+struct Request {
+    /// User to run the command as
+    user: String,
+    /// Group to run the command as, instead of the primary group of `user`
+    group: Option<String>,
+    /// Executable as given by the caller followed by its arguments
+    command: Vec<String>,
+    /// Only authenticate and update the cached credentials, see `sudo -v`
+    #[serde(default)]
+    validate: bool,
+    /// Edit the files following `sudoedit` in `command`, see `sudo -e`
+    #[serde(default)]
+    edit: bool,
+    /// Variables the caller asks to keep, all of them if empty, see `sudo -E`
+    preserve_env: Option<Vec<String>>,
+    /// Current directory of the caller, to resolve relative paths and to run
+    /// the command in if the caller may search it, see `may_search`
+    #[serde(default)]
+    cwd: String,
+    /// Directory to run the command in, see `sudo --chdir`
+    chdir: Option<String>,
+    /// Terminal of the caller, only recorded in the audit log
+    tty: Option<String>,
+    /// Environment of the caller, filtered by `command_env`
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    options: RunOptions,
+}
+
+/// Escape `arg` with backslashes for the command line of `sudo -s`, except
+/// for letters, digits, `_`, `-` and `$`, like sudo does so variables are
+/// still expanded by the shell.
+fn shell_quote(arg: &str) -> String {
+    let mut quoted = String::new();
+    for c in arg.chars() {
+        if !(c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '$') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+/// Join `path` to `cwd` unless it is absolute, dropping `.` components.
+fn absolute_path(cwd: &str, path: &str) -> String {
+    let path: PathBuf = Path::new(cwd).join(path).components().collect();
+    path.into_os_string().into_string().unwrap()
+}
+
+/// Open `path` for reading and writing, if `target` may do so. Unlike the
+/// superuser, other users may not create files this way.
+fn open_for_edit(path: &str, target: Target) -> io::Result<File> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_file() => {
+            return Err(io::Error::other("not a regular file"));
+        }
+        Ok(metadata) if target.uid != 0 => {
+            let mask = if metadata.uid() == target.uid {
+                0o600
+            } else if metadata.gid() == target.gid {
+                0o060
+            } else {
+                0o006
+            };
+            if metadata.mode() & mask != mask {
+                return Err(io::Error::from_raw_os_error(EACCES));
             }
         }
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound && target.uid == 0 => {}
+        Err(err) => return Err(err),
     }
 
-    // FIXME move to libredox
-    unsafe extern "C" {
-        safe fn redox_cur_procfd_v0() -> usize;
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(target.uid == 0)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// IDs of the groups of `account`, including its primary group.
+fn group_ids(account: &Account, accounts: &Accounts) -> Vec<usize> {
+    account
+        .groups
+        .iter()
+        .filter_map(|group| accounts.group_id(group).ok())
+        .chain([account.gid])
+        .collect()
+}
+
+/// Refuse to edit `path` if a directory leading to it is a symbolic link or
+/// may be written by `caller`, who could then swap the file for another one
+/// while the daemon opens it as the target user.
+fn check_parents(path: &str, caller: &Account, accounts: &Accounts) -> io::Result<()> {
+    let gids = group_ids(caller, accounts);
+    for dir in Path::new(path).ancestors().skip(1) {
+        let metadata = fs::symlink_metadata(dir)?;
+        if metadata.file_type().is_symlink() {
+            return Err(io::Error::from_raw_os_error(ELOOP));
+        }
+        let mode = metadata.mode();
+        let writable = (metadata.uid() as usize == caller.uid && mode & 0o200 != 0)
+            || (gids.contains(&(metadata.gid() as usize)) && mode & 0o020 != 0)
+            || mode & 0o002 != 0;
+        if writable {
+            return Err(io::Error::from_raw_os_error(EACCES));
+        }
     }
+    Ok(())
+}
 
-    // Elevate privileges of our own process with help from the sudo daemon
-    file.call_wo(
-        &libredox::call::dup(redox_cur_procfd_v0(), &[])
-            .unwrap()
-            .to_ne_bytes(),
-        syscall::CallFlags::FD,
-        &[],
-    )
-    .unwrap();
+/// Whether `caller` may search the absolute path `dir` and the directories
+/// leading to it. The daemon only runs commands in directories the caller
+/// could enter itself.
+fn may_search(dir: &str, caller: &Account, accounts: &Accounts) -> bool {
+    if !dir.starts_with('/') {
+        return false;
+    }
+    if caller.uid == 0 {
+        return Path::new(dir).is_dir();
+    }
+
+    let gids = group_ids(caller, accounts);
+    Path::new(dir).ancestors().all(|dir| {
+        let Ok(metadata) = fs::metadata(dir) else {
+            return false;
+        };
+        let mask = if metadata.uid() as usize == caller.uid {
+            0o100
+        } else if gids.contains(&(metadata.gid() as usize)) {
+            0o010
+        } else {
+            0o001
+        };
+        metadata.is_dir() && metadata.mode() & mask != 0
+    })
+}
+
+/// Let the caller edit `files`, opened for the target user, through copies
+/// owned by the caller, and write the changes back, see `sudo -e`.
+fn edit_files(files: Vec<(String, File)>) -> ! {
+    let mut copies = Vec::new();
+    for (i, (path, mut file)) in files.into_iter().enumerate() {
+        let name = Path::new(&path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("file");
+        let copy_path = env::temp_dir().join(format!("sudoedit-{}-{}-{}", process::id(), i, name));
+
+        let mut contents = Vec::new();
+        let copied = file.read_to_end(&mut contents).and_then(|_| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&copy_path)?
+                .write_all(&contents)
+        });
+        if let Err(err) = copied {
+            eprintln!("sudo: failed to copy {}: {}", path, err);
+            for (_, _, copy_path, _) in copies {
+                let _ = fs::remove_file(copy_path);
+            }
+            exit(1);
+        }
+        copies.push((path, file, copy_path, contents));
+    }
+
+    let editor = ["SUDO_EDITOR", "VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string());
+    let mut words = editor.split_whitespace();
+    let status = Command::new(words.next().unwrap())
+        .args(words)
+        .args(copies.iter().map(|(_, _, copy_path, _)| copy_path))
+        .status();
+    let edited = match status {
+        Ok(status) if status.success() => true,
+        Ok(_) => {
+            eprintln!("sudo: {} failed, files left unchanged", editor);
+            false
+        }
+        Err(err) => {
+            eprintln!("sudo: failed to run {}: {}", editor, err);
+            false
+        }
+    };
+
+    let mut failed = !edited;
+    for (path, mut file, copy_path, original) in copies {
+        if edited {
+            let written = fs::read(&copy_path).and_then(|contents| {
+                if contents == original {
+                    return Ok(());
+                }
+                file.seek(SeekFrom::Start(0))?;
+                file.set_len(0)?;
+                file.write_all(&contents)
+            });
+            if let Err(err) = written {
+                // Keep the copy so the changes are not lost
+                eprintln!(
+                    "sudo: failed to write {}, changes left in {}: {}",
+                    path,
+                    copy_path.display(),
+                    err
+                );
+                failed = true;
+                continue;
+            }
+        }
+        let _ = fs::remove_file(copy_path);
+    }
+    exit(if failed { 1 } else { 0 });
+}
+
+/// Print the rules applying to the caller, or with `argv` the command line
+/// that would be run for it if the policy allows it, see `sudo -l`.
+fn list_privileges(
+    target: &User<auth::Basic>,
+    group: Option<&str>,
+    argv: Option<Vec<String>>,
+    cwd: String,
+) -> ! {
+    let file = libredox::Fd::open("/scheme/sudo/list", O_CLOEXEC, 0)
+        .unwrap_or_else(|err| daemon_failed(err));
+
+    if let Some(argv) = argv {
+        let request = Request {
+            user: target.user.clone(),
+            group: group.map(String::from),
+            command: argv,
+            validate: false,
+            edit: false,
+            preserve_env: None,
+            cwd,
+            chdir: None,
+            tty: None,
+            env: HashMap::new(),
+            options: RunOptions::default(),
+        };
+        match file.write(toml::to_string(&request).unwrap().as_bytes()) {
+            Ok(_) => {}
+            // Only reported through the exit status
+            Err(err) if err.errno() == EACCES => exit(1),
+            Err(err) if err.errno() == ENOENT => {
+                resolve_failed(&request.command[0], ResolveError::NotFound)
+            }
+            Err(err) if err.errno() == EINVAL => {
+                resolve_failed(&request.command[0], ResolveError::Relative)
+            }
+            Err(err) => daemon_failed(err),
+        }
+    }
+
+    let mut buf = [0; 4096];
+    loop {
+        let count = file.read(&mut buf).unwrap_or_else(|err| daemon_failed(err));
+        if count == 0 {
+            break;
+        }
+        io::stdout().write_all(&buf[..count]).unwrap();
+    }
+    exit(0);
+}
+
+/// Read by the client from a `/scheme/sudo` handle once its request has been
+/// written, or refused with `EACCES`.
+#[derive(Serialize, Deserialize)]
+struct Reply {
+    /// The password of the caller has to be written before going on
+    password: bool,
+    /// The command line with the absolute path of the executable, which is
+    /// what the daemon runs
+    command: Vec<String>,
+    /// Restrictions of the matching rule the daemon applies to the command
+    #[serde(default)]
+    restrictions: Restrictions,
+    /// Milliseconds until the password of the caller is checked again after
+    /// too many failures, see `Throttle`
+    #[serde(default)]
+    retry_after: u64,
+}
+
+/// Restrictions of the rule allowing a command, applied by the daemon.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Restrictions {
+    /// Schemes the namespace of the command is restricted to
+    schemes: Option<Vec<String>>,
+    /// Seconds after which the command is killed
+    timeout: Option<u64>,
+    /// Directory the command runs in
+    chdir: Option<String>,
+    /// File mode creation mask of the command
+    umask: Option<u32>,
+    /// Whether the session of the command is recorded
+    #[serde(default)]
+    log_io: bool,
+}
+
+impl Restrictions {
+    fn new(defaults: &Defaults, rule: &Rule, request: &Request) -> Restrictions {
+        Restrictions {
+            schemes: rule.schemes.clone(),
+            timeout: rule.timeout,
+            chdir: match rule.chdir.as_deref() {
+                Some(ANY_DIR) => request.chdir.clone(),
+                chdir => chdir.map(String::from),
+            },
+            umask: rule.umask,
+            log_io: defaults.log_io || rule.log_io,
+        }
+    }
+}
+
+fn read_reply(file: &libredox::Fd) -> Option<Reply> {
+    let mut reply = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        match file.read(&mut buf).ok()? {
+            0 => break,
+            count => reply.extend_from_slice(&buf[..count]),
+        }
+    }
+    toml::from_str(std::str::from_utf8(&reply).ok()?).ok()
+}
+
+/// Replace `%u` in a password prompt by `user`, `%U` by `target`, `%h` by
+/// the host name and `%%` by `%`.
+fn expand_prompt(prompt: &str, user: &str, target: &str) -> String {
+    let mut expanded = String::new();
+    let mut chars = prompt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => expanded += user,
+            Some('U') => expanded += target,
+            Some('h') => expanded += &hostname(),
+            Some('%') => expanded.push('%'),
+            Some(c) => {
+                expanded.push('%');
+                expanded.push(c);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Read a password from a line of the standard input, see `sudo -S`. It is
+/// read byte by byte to leave the rest of the input to the command.
+fn read_stdin_password() -> Option<String> {
+    let mut password = Vec::new();
+    let mut byte = [0];
+    loop {
+        match libredox::call::read(0, &mut byte) {
+            Ok(1) if byte[0] != b'\n' => password.push(byte[0]),
+            Ok(1) => break,
+            // The end of the input also ends a last line without newline
+            _ if password.is_empty() => return None,
+            _ => break,
+        }
+    }
+    if password.last() == Some(&b'\r') {
+        password.pop();
+    }
+    String::from_utf8(password).ok()
+}
+
+fn resolve_failed(cmd: &str, err: ResolveError) -> ! {
+    match err {
+        ResolveError::NotFound => eprintln!("sudo: {}: command not found", cmd),
+        ResolveError::Relative => eprintln!("sudo: {}: relative paths are not allowed", cmd),
+    }
+    exit(1);
+}
+
+/// Report an error returned by the sudo daemon which the caller has no more
+/// specific message for.
+fn daemon_failed(err: libredox::error::Error) -> ! {
+    match err.errno() {
+        ESRCH => eprintln!("sudo: unknown user or group"),
+        ENOLCK => eprintln!("sudo: the user database is busy, try again later"),
+        EACCES => eprintln!("sudo: permission denied"),
+        EPERM => eprintln!("sudo: failed to change to the target user"),
+        EAGAIN => eprintln!("sudo: too many failed attempts, try again later"),
+        EBADMSG | EINVAL => eprintln!("sudo: invalid request"),
+        ENODEV => eprintln!("sudo: the sudo daemon is not running"),
+        _ => eprintln!("sudo: unexpected error from the sudo daemon: {}", err),
+    }
+    exit(1);
+}
 
-    // FIXME perhaps keep the original namespace available in a subdirectory of the namespace we switch to?
-    let ns = file.openat("ns", O_CLOEXEC, 0).unwrap();
-    libredox::call::setns(ns.into_raw()).unwrap();
+fn load_defaults() -> Defaults {
+    match Sudoers::load() {
+        Ok(sudoers) => sudoers.defaults,
+        Err(err) => {
+            eprintln!("sudo: failed to load {}: {}", SUDOERS_FILE, err);
+            exit(1);
+        }
+    }
+}
 
-    run_command_as_root(&cmd, &args.collect());
+/// Forget the cached credentials of the caller for its session, or for
+/// every session if `all` is set.
+fn invalidate_credentials(all: bool) {
+    let file = libredox::Fd::open("/scheme/sudo/invalidate", O_CLOEXEC, 0)
+        .unwrap_or_else(|err| daemon_failed(err));
+    file.write(if all { b"all" } else { b"session" })
+        .unwrap_or_else(|err| daemon_failed(err));
 }
 
 enum Policy {
     Deny,
-    Authenticate,
+    /// The command may be run, but not with the options of the caller, see
+    /// `sudo -E` and `sudo --chdir`.
+    DenyOption,
+    Authenticate {
+        timestamp_timeout: Duration,
+        restrictions: Restrictions,
+    },
+    Allow {
+        restrictions: Restrictions,
+    },
 }
 
-fn policy_for_user(uid: u32) -> Policy {
-    let users = AllUsers::authenticator(Config::default()).unwrap_or_exit(1);
-    let groups = AllGroups::new(Config::default()).unwrap_or_exit(1);
+/// Parse a request written by the client, refusing it with `EBADMSG` if it
+/// is malformed.
+fn parse_request(buf: &[u8]) -> Result<Request> {
+    let request = std::str::from_utf8(buf).map_err(|_| Error::new(EBADMSG))?;
+    toml::from_str(request).map_err(|_| Error::new(EBADMSG))
+}
+
+/// Replace the executable of a request by its absolute path. Fails with
+/// `ENOENT` if it is not found and `EINVAL` if it is a relative path the
+/// policy does not allow.
+fn resolve_request(sudoers: &Sudoers, request: &mut Request) -> Result<()> {
+    let cmd = request.command.first().ok_or(Error::new(EBADMSG))?;
+    request.command[0] = match sudoers.defaults.resolve_command(cmd, &request.cwd) {
+        Ok(path) => path,
+        Err(ResolveError::NotFound) => return Err(Error::new(ENOENT)),
+        Err(ResolveError::Relative) => return Err(Error::new(EINVAL)),
+    };
+    Ok(())
+}
 
-    let user = users.get_by_id(uid as usize).unwrap_or_exit(1);
+/// The rules applying to `uid`, as listed by `sudo -l`.
+fn list_rules(sudoers: &Sudoers, accounts: &Accounts, uid: u32) -> Result<String> {
+    let user = accounts.by_id(uid as usize)?;
 
-    let sudo_group = groups.get_by_name("sudo").unwrap_or_exit(1);
-    if !sudo_group.users.iter().any(|name| name == &user.user) {
-        return Policy::Deny;
+    let mut rules = sudoers.rules_for(&user.name, &user.groups).peekable();
+    if rules.peek().is_none() {
+        return Ok(format!("User {} may not run sudo.\n", user.name));
+    }
+    let mut text = format!("User {} may run the following commands:\n", user.name);
+    for rule in rules {
+        text += &format!("    {}\n", rule);
     }
+    Ok(text)
+}
+
+fn policy_for_user(
+    sudoers: &Sudoers,
+    accounts: &Accounts,
+    uid: u32,
+    request: &Request,
+) -> Result<Policy> {
+    let user = accounts.by_id(uid as usize)?;
 
-    Policy::Authenticate
+    let timestamp_timeout = sudoers.defaults.timestamp_duration();
+
+    if request.validate {
+        // Any rule applying to the user allows them to authenticate, which
+        // they need not if none of these rules asks for a password
+        let mut rules = sudoers.rules_for(&user.name, &user.groups).peekable();
+        let policy = if rules.peek().is_none() {
+            Policy::Deny
+        } else if rules.all(|rule| rule.nopasswd) {
+            Policy::Allow {
+                restrictions: Restrictions::default(),
+            }
+        } else {
+            Policy::Authenticate {
+                timestamp_timeout,
+                restrictions: Restrictions::default(),
+            }
+        };
+        return Ok(policy);
+    }
+
+    let policy = match sudoers.find_rule(
+        &user.name,
+        &user.groups,
+        &request.user,
+        request.group.as_deref(),
+        Some(&request.command),
+    ) {
+        Some(rule) if request.preserve_env.is_some() && !rule.setenv => Policy::DenyOption,
+        Some(rule) if request.chdir.is_some() && rule.chdir.as_deref() != Some(ANY_DIR) => {
+            Policy::DenyOption
+        }
+        Some(rule) if rule.nopasswd => Policy::Allow {
+            restrictions: Restrictions::new(&sudoers.defaults, rule, request),
+        },
+        Some(rule) => Policy::Authenticate {
+            timestamp_timeout,
+            restrictions: Restrictions::new(&sudoers.defaults, rule, request),
+        },
+        None => Policy::Deny,
+    };
+    Ok(policy)
+}
+
+/// Check the password of the user `uid`. Fails with `ENOLCK` if the user
+/// database cannot be read and `ESRCH` if the user does not exist.
+fn verify_password(uid: usize, password: &str) -> Result<bool> {
+    let users = AllUsers::authenticator(auth_config()).map_err(|_| Error::new(ENOLCK))?;
+    let user = users.get_by_id(uid).ok_or(Error::new(ESRCH))?;
+    Ok(user.verify_passwd(password))
 }
 
-fn run_command_as_root(cmd: &str, args: &Vec<String>) -> ! {
-    let mut command = Command::new(&cmd);
-    for arg in args {
-        command.arg(&arg);
+/// The environment of the caller, as sent to the daemon. Variables which are
+/// not valid UTF-8 are left out.
+fn caller_env() -> HashMap<String, String> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+/// Select the environment of the command from `vars`, the one of the caller,
+/// as allowed by the policy. `preserve_env` lists the variables the caller
+/// asked to keep, all of them if empty. A login shell only gets `TERM` and
+/// `TTY`.
+fn command_env(
+    defaults: &Defaults,
+    vars: impl IntoIterator<Item = (String, String)>,
+    preserve_env: Option<&[String]>,
+    login: bool,
+) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(name, _)| match login {
+            true => name == "TERM" || name == "TTY",
+            false => defaults.keeps_var(name, preserve_env),
+        })
+        .collect();
+    if !defaults.secure_path.is_empty() {
+        vars.push(("PATH".into(), defaults.secure_path.clone()));
     }
+    vars
+}
 
-    command.uid(0);
-    command.gid(0);
-    command.env("USER", "root");
-    command.env("UID", "0");
-    command.env("GROUPS", "0");
+/// The command running `argv`, whose first element is the path of the
+/// executable, as `target` with `gid` as primary group and `env` as
+/// environment, completed by the variables describing the target user.
+///
+/// It runs in `cwd` if given. With `login`, `argv` is run like a login shell
+/// instead: in the home directory of `target` and with `-` prepended to its
+/// name. The directory and file mode creation mask of `restrictions` apply
+/// last.
+fn command(
+    argv: &[String],
+    target: &Account,
+    gid: usize,
+    env: Vec<(String, String)>,
+    login: bool,
+    cwd: Option<&str>,
+    restrictions: &Restrictions,
+) -> Command {
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
 
-    let err = command.exec();
+    command.env_clear();
+    command.envs(env);
+    if let Some(cwd) = cwd.filter(|cwd| !cwd.is_empty()) {
+        command.current_dir(cwd);
+    }
+    if login {
+        let name = Path::new(&argv[0])
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&argv[0]);
+        command.arg0(format!("-{}", name));
+        command.current_dir(&target.home);
+        command.env("SHELL", &target.shell);
+    }
+    command.uid(target.uid as u32);
+    command.gid(gid as u32);
+    command.env("USER", &target.name);
+    command.env("UID", target.uid.to_string());
+    command.env("HOME", &target.home);
+    command.env("GROUPS", gid.to_string());
+    if let Some(dir) = &restrictions.chdir {
+        command.current_dir(dir);
+    }
+    if let Some(umask) = restrictions.umask {
+        unsafe {
+            command.pre_exec(move || {
+                libc::umask(umask as libc::mode_t);
+                Ok(())
+            })
+        };
+    }
+    command
+}
 
-    eprintln!("sudo: failed to execute {}: {}", cmd, err);
-    exit(1);
+/// Execute `argv` for root, which needs no daemon, see `command`.
+///
+/// With a timeout, sudo waits for the command instead of executing it in
+/// its own process, to kill its process group once the timeout expires. With
+/// a `log`, the command runs on a pseudo terminal whose session is recorded
+/// to it.
+fn run_command(
+    argv: &[String],
+    target: &Account,
+    gid: usize,
+    env: Vec<(String, String)>,
+    options: &RunOptions,
+    restrictions: &Restrictions,
+    log: Option<File>,
+) -> ! {
+    let mut command = command(argv, target, gid, env, options.login, None, restrictions);
+    close_from(options.close_from);
+    let exec_failed = |err: io::Error| -> ! {
+        eprintln!("sudo: failed to execute {}: {}", argv[0], err);
+        exit(1);
+    };
+
+    if options.background {
+        if log.is_some() {
+            eprintln!("sudo: recorded sessions cannot run in the background");
+            exit(1);
+        }
+        command.stdin(Stdio::null());
+        detach();
+    }
+
+    if let Some(log) = log {
+        let stdio = |fd: RawFd| {
+            let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+            fd.try_clone_to_owned()
+                .unwrap_or_else(|err| exec_failed(err))
+        };
+        let size = options.terminal_size.unwrap_or((80, 30));
+        let recording = Recording::new(&mut command, log, stdio(0), File::from(stdio(1)), size)
+            .unwrap_or_else(|err| {
+                eprintln!("sudo: failed to record the session: {}", err);
+                exit(1);
+            });
+        let raw = termion::is_tty(&io::stdin())
+            .then(|| io::stdout().into_raw_mode().ok())
+            .flatten();
+        let child = spawn_in_group(command, true).unwrap_or_else(|err| exec_failed(err));
+        let status = wait_command(
+            &Mutex::new(child),
+            &argv[0],
+            restrictions.timeout,
+            &mut io::stderr(),
+        );
+        recording.finish();
+        drop(raw);
+        exit(status);
+    }
+
+    if restrictions.timeout.is_none() {
+        exec_failed(command.exec());
+    }
+
+    // Taking the terminal only if sudo has it, it may run in the background
+    let foreground = unsafe { libc::tcgetpgrp(0) == libc::getpgrp() };
+    let child = spawn_in_group(command, foreground).unwrap_or_else(|err| exec_failed(err));
+    let status = wait_command(
+        &Mutex::new(child),
+        &argv[0],
+        restrictions.timeout,
+        &mut io::stderr(),
+    );
+    if foreground {
+        set_foreground(unsafe { libc::getpgrp() });
+    }
+    exit(status);
+}
+
+/// Spawn `command` in a process group of its own, so `wait_command` can kill
+/// the processes it starts along with it. With `foreground`, the group is
+/// made the foreground one of the terminal the command reads from.
+fn spawn_in_group(mut command: Command, foreground: bool) -> io::Result<Child> {
+    command.process_group(0);
+    if foreground {
+        unsafe {
+            command.pre_exec(|| {
+                set_foreground(libc::getpgrp());
+                Ok(())
+            })
+        };
+    }
+    command.spawn()
+}
+
+/// Make `pgrp` the foreground process group of the terminal on the standard
+/// input. `SIGTTOU` is ignored meanwhile, as it stops processes changing the
+/// foreground group from the background.
+fn set_foreground(pgrp: libc::pid_t) {
+    unsafe {
+        let handler = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(0, pgrp);
+        libc::signal(libc::SIGTTOU, handler);
+    }
+}
+
+/// The number of file descriptors a process may have open, which bounds
+/// those the caller may pass on with `sudo --close-from`.
+fn open_max() -> RawFd {
+    match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        max if max > 0 => RawFd::try_from(max).unwrap_or(RawFd::MAX),
+        _ => 1024,
+    }
+}
+
+/// Keep the file descriptors from `first` on from being inherited by the
+/// command, see `sudo --close-from`. They are only marked close-on-exec, as
+/// sudo may still use some of them.
+fn close_from(first: RawFd) {
+    for fd in first..open_max() {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags >= 0 && flags & libc::FD_CLOEXEC == 0 {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) };
+        }
+    }
+}
+
+/// Continue in a child process leading a new session without a controlling
+/// terminal, while sudo itself exits, see `sudo -b`.
+fn detach() {
+    match unsafe { libc::fork() } {
+        -1 => {
+            eprintln!("sudo: failed to fork: {}", io::Error::last_os_error());
+            exit(1);
+        }
+        0 => {
+            unsafe { libc::setsid() };
+        }
+        _ => exit(0),
+    }
+}
+
+/// Wait for the command `name`, spawned by `spawn_in_group`, to exit, killing
+/// its process group once `timeout` seconds have passed, and return the exit
+/// status of sudo. Errors are reported to `stderr`, the one of the caller.
+fn wait_command(
+    child: &Mutex<Child>,
+    name: &str,
+    timeout: Option<u64>,
+    stderr: &mut impl Write,
+) -> i32 {
+    let start = Instant::now();
+    loop {
+        // Locked while checking, so `Handle::Running` never signals the
+        // process group once the command is reaped and its ID may be reused
+        let mut child = child.lock().unwrap();
+        match child.try_wait() {
+            Ok(Some(status)) => return status.code().unwrap_or(1),
+            Ok(None) => match timeout {
+                Some(timeout) if start.elapsed() >= Duration::from_secs(timeout) => {
+                    let _ = writeln!(stderr, "sudo: {} timed out after {} seconds", name, timeout);
+                    // Along with the processes it started, see `spawn_in_group`
+                    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                    let _ = child.wait();
+                    return TIMEOUT_STATUS;
+                }
+                _ => {}
+            },
+            Err(err) => {
+                let _ = writeln!(stderr, "sudo: failed to wait for {}: {}", name, err);
+                return 1;
+            }
+        }
+        drop(child);
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
 }
 
 struct Scheme {
     next_fd: usize,
     handles: HashMap<usize, Handle>,
+    /// Time of the last successful authentication of a user in a session,
+    /// see `caller_session`
+    tickets: HashMap<(u32, usize), Instant>,
+    /// Position of the next read of the reply of each handle, which is read
+    /// from the start again after every write, see `Reply`
+    reply_pos: HashMap<usize, usize>,
+    throttle: Throttle,
+    audit: AuditLog,
+    cache: Cache,
+}
+
+/// The credentials an authenticated process is switched to.
+#[derive(Clone, Copy)]
+struct Target {
+    uid: u32,
+    gid: u32,
+}
+
+impl Target {
+    const ROOT: Target = Target { uid: 0, gid: 0 };
+}
+
+/// The session of the calling process, which credentials are cached for. It
+/// is looked up by the daemon as the `TTY` sent by the caller could name any
+/// terminal. `None` if it is unknown, in which case nothing is cached.
+fn caller_session(ctx: &CallerCtx) -> Option<usize> {
+    let sid = unsafe { libc::getsid(ctx.pid as libc::pid_t) };
+    usize::try_from(sid).ok().filter(|&sid| sid != 0)
 }
+
+/// Splits the terminal off a path opened at the scheme root. `su` and
+/// `passwd` append their `TTY` to the path of the daemon, as in
+/// `/scheme/sudo/su/scheme/pty/1`. Like the `TTY` of a `Request`, it is only
+/// recorded in the audit log.
+fn caller_tty(path: &str) -> (&str, Option<String>) {
+    match path.split_once('/') {
+        Some((name, tty)) => (name, Some(format!("/{}", tty))),
+        None => (path, None),
+    }
+}
+
 enum Handle {
-    AwaitingPassword { uid: u32 },
-    AwaitingRootPassword,
-    AwaitingContextFd,
-    AwaitingNamespaceFetch { ns: libredox::Fd },
+    /// `session` is the one of the caller, see `caller_session`.
+    AwaitingRequest {
+        uid: u32,
+        session: Option<usize>,
+    },
+    /// `target` is `None` if the caller only validates its credentials.
+    AwaitingPassword {
+        uid: u32,
+        session: Option<usize>,
+        request: Request,
+        target: Option<Target>,
+        restrictions: Restrictions,
+    },
+    /// `tty` is the terminal of the caller, if it told it, see `caller_tty`.
+    AwaitingRootPassword {
+        uid: u32,
+        tty: Option<String>,
+    },
+    /// The caller of su is elevated to `target` once it sent its process.
+    AwaitingContextFd {
+        target: Target,
+    },
+    AwaitingNamespaceFetch {
+        ns: libredox::Fd,
+    },
+    /// The command of `request` runs once the caller sent the file
+    /// descriptors it inherits and opened `run`, see `Scheme::run`.
+    AwaitingFds {
+        uid: u32,
+        target: Target,
+        request: Request,
+        restrictions: Restrictions,
+        fds: Vec<OwnedFd>,
+    },
+    /// `child` is the command run for the caller, which may send it one of
+    /// `FORWARDED_SIGNALS` by writing its number.
+    Running {
+        child: Arc<Mutex<Child>>,
+    },
+    Validated,
+    /// The caller `uid` may open `files` for `target` by index, see `sudo -e`.
+    Editing {
+        uid: u32,
+        target: Target,
+        files: Vec<String>,
+    },
+    /// The request was refused, `command` is kept for the error message.
+    Denied {
+        command: Vec<String>,
+    },
 
-    AwaitingPasswordForPasswd { uid: u32 },
-    AwaitingNewPassword { uid: u32 },
+    Invalidate {
+        uid: u32,
+        session: Option<usize>,
+    },
+    /// `text` is read by `sudo -l`, from `pos` on.
+    List {
+        uid: u32,
+        text: String,
+        pos: usize,
+    },
+    /// Only opened by root, see `Cache::reload`.
+    Reload,
+
+    AwaitingPasswordForPasswd {
+        uid: u32,
+        tty: Option<String>,
+    },
+    AwaitingNewPassword {
+        uid: u32,
+    },
 
     Placeholder,
 
     SchemeRoot,
 }
 
+impl Handle {
+    /// The state of a handle once its caller has been authenticated.
+    fn granted(
+        uid: u32,
+        target: Option<Target>,
+        request: Request,
+        restrictions: Restrictions,
+    ) -> Handle {
+        match target {
+            Some(target) if request.edit => Handle::Editing {
+                uid,
+                target,
+                files: request.command[1..].to_vec(),
+            },
+            Some(target) => Handle::AwaitingFds {
+                uid,
+                target,
+                request,
+                restrictions,
+                fds: Vec::new(),
+            },
+            None => Handle::Validated,
+        }
+    }
+}
+
 impl SchemeSync for Scheme {
     fn scheme_root(&mut self) -> Result<usize> {
         let fd = self.next_fd;
@@ -189,12 +1741,61 @@ impl SchemeSync for Scheme {
         ctx: &CallerCtx,
     ) -> Result<OpenResult> {
         let handle = match self.handles.get_mut(&dirfd).ok_or(Error::new(EBADF))? {
-            Handle::SchemeRoot => match path {
-                "" => Handle::AwaitingPassword { uid: ctx.uid },
-                "su" => Handle::AwaitingRootPassword,
-                "passwd" => Handle::AwaitingPasswordForPasswd { uid: ctx.uid },
+            Handle::SchemeRoot => match caller_tty(path) {
+                ("", None) => Handle::AwaitingRequest {
+                    uid: ctx.uid,
+                    session: caller_session(ctx),
+                },
+                ("su", tty) => Handle::AwaitingRootPassword { uid: ctx.uid, tty },
+                ("passwd", tty) => Handle::AwaitingPasswordForPasswd { uid: ctx.uid, tty },
+                ("invalidate", None) => Handle::Invalidate {
+                    uid: ctx.uid,
+                    session: caller_session(ctx),
+                },
+                ("list", None) => Handle::List {
+                    uid: ctx.uid,
+                    text: list_rules(&*self.cache.sudoers()?, &*self.cache.accounts()?, ctx.uid)?,
+                    pos: 0,
+                },
+                ("reload", None) if ctx.uid == 0 => Handle::Reload,
+                ("reload", None) => return Err(Error::new(EACCES)),
                 _ => return Err(Error::new(ENOENT)),
             },
+            Handle::Editing { uid, target, files } => {
+                let file = path
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| files.get(i))
+                    .ok_or(Error::new(ENOENT))?;
+                let accounts = self.cache.accounts()?;
+                let file = check_parents(file, accounts.by_id(*uid as usize)?, &accounts)
+                    .and_then(|()| open_for_edit(file, *target))
+                    .map_err(|err| Error::new(err.raw_os_error().unwrap_or(EINVAL)))?;
+                return Ok(OpenResult::OtherScheme {
+                    fd: file.into_raw_fd() as usize,
+                });
+            }
+            Handle::AwaitingFds { .. } => {
+                if path != "run" {
+                    return Err(Error::new(ENOENT));
+                }
+                let Some(Handle::AwaitingFds {
+                    uid,
+                    target,
+                    request,
+                    restrictions,
+                    fds,
+                }) = self.handles.insert(dirfd, Handle::Placeholder)
+                else {
+                    unreachable!()
+                };
+                // The caller only gets to retry by sending a new request
+                let (child, status) = self.run(uid, target, &request, &restrictions, fds)?;
+                self.handles.insert(dirfd, Handle::Running { child });
+                return Ok(OpenResult::OtherScheme {
+                    fd: OwnedFd::from(status).into_raw_fd() as usize,
+                });
+            }
             Handle::AwaitingNamespaceFetch { .. } => {
                 if path != "ns" {
                     return Err(Error::new(ENOENT));
@@ -218,6 +1819,87 @@ impl SchemeSync for Scheme {
         })
     }
 
+    fn read(
+        &mut self,
+        id: usize,
+        buf: &mut [u8],
+        _off: u64,
+        _flags: u32,
+        _ctx: &CallerCtx,
+    ) -> Result<usize> {
+        match self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::List { text, pos, .. } => {
+                let count = (text.len() - *pos).min(buf.len());
+                buf[..count].copy_from_slice(&text.as_bytes()[*pos..*pos + count]);
+                *pos += count;
+                return Ok(count);
+            }
+            // Milliseconds until the password is checked again, see
+            // `retry_delay`
+            Handle::AwaitingRootPassword { uid, .. }
+            | Handle::AwaitingPasswordForPasswd { uid, .. } => {
+                let remaining = self.throttle.remaining(*uid).as_millis().to_string();
+                let count = remaining.len().min(buf.len());
+                buf[..count].copy_from_slice(&remaining.as_bytes()[..count]);
+                return Ok(count);
+            }
+            _ => {}
+        }
+
+        let reply = match self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::AwaitingPassword {
+                uid,
+                request,
+                restrictions,
+                ..
+            } => Reply {
+                password: true,
+                command: request.command.clone(),
+                restrictions: restrictions.clone(),
+                retry_after: self.throttle.remaining(*uid).as_millis() as u64,
+            },
+            Handle::AwaitingFds {
+                request,
+                restrictions,
+                ..
+            } => Reply {
+                password: false,
+                command: request.command.clone(),
+                restrictions: restrictions.clone(),
+                retry_after: 0,
+            },
+            Handle::Denied { command } => Reply {
+                password: false,
+                command: command.clone(),
+                restrictions: Restrictions::default(),
+                retry_after: 0,
+            },
+            Handle::Validated => Reply {
+                password: false,
+                command: Vec::new(),
+                restrictions: Restrictions::default(),
+                retry_after: 0,
+            },
+            Handle::Editing { files, .. } => Reply {
+                password: false,
+                command: std::iter::once(SUDOEDIT.to_string())
+                    .chain(files.iter().cloned())
+                    .collect(),
+                restrictions: Restrictions::default(),
+                retry_after: 0,
+            },
+            _ => return Err(Error::new(EBADF)),
+        };
+        let reply = toml::to_string(&reply).map_err(|_| Error::new(EINVAL))?;
+
+        let pos = self.reply_pos.entry(id).or_default();
+        let rest = reply.as_bytes().get(*pos..).unwrap_or_default();
+        let count = rest.len().min(buf.len());
+        buf[..count].copy_from_slice(&rest[..count]);
+        *pos += count;
+        Ok(count)
+    }
+
     fn write(
         &mut self,
         id: usize,
@@ -226,70 +1908,351 @@ impl SchemeSync for Scheme {
         _flags: u32,
         _ctx: &CallerCtx,
     ) -> Result<usize> {
+        self.reply_pos.remove(&id);
         let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
 
         let validate_utf8 = |buf| std::str::from_utf8(buf).map_err(|_| Error::new(EINVAL));
 
         match std::mem::replace(handle, Handle::Placeholder) {
-            Handle::AwaitingPassword { uid } => {
-                let users = AllUsers::authenticator(Config::default()).unwrap_or_exit(1);
-                let user = users.get_by_id(uid as usize).unwrap_or_exit(1);
+            Handle::AwaitingRequest { uid, session } => {
+                let mut request = parse_request(buf)?;
+
+                let sudoers = self.cache.sudoers()?;
+                let accounts = self.cache.accounts()?;
+                if request.edit {
+                    // Not an executable, but the files to edit
+                    let files = request.command.get(1..).unwrap_or_default();
+                    if request.command.first().map(String::as_str) != Some(SUDOEDIT)
+                        || files.is_empty()
+                        || !files.iter().all(|file| file.starts_with('/'))
+                    {
+                        return Err(Error::new(EBADMSG));
+                    }
+                } else if !request.validate {
+                    // Pin the executable before checking the policy, the
+                    // daemon runs the path it checked
+                    resolve_request(&sudoers, &mut request)?;
+                }
 
-                match policy_for_user(uid) {
+                let target_user = accounts.by_name(&request.user)?;
+                let gid = match &request.group {
+                    Some(name) => accounts.group_id(name)?,
+                    None => target_user.gid,
+                };
+                let target = (!request.validate).then_some(Target {
+                    uid: target_user.uid as u32,
+                    gid: gid as u32,
+                });
+
+                let mut event = AuditEvent {
+                    service: "sudo",
+                    uid,
+                    target: target.map(|target| target.uid),
+                    command: target.map(|_| &*request.command),
+                    tty: request.tty.as_deref(),
+                    outcome: "granted",
+                };
+
+                let next = match policy_for_user(&sudoers, &accounts, uid, &request)? {
                     Policy::Deny => {
-                        *handle = Handle::AwaitingPassword { uid };
+                        event.outcome = "denied";
+                        self.audit.record(event);
+                        *handle = Handle::Denied {
+                            command: request.command,
+                        };
+                        return Err(Error::new(EACCES));
+                    }
+                    Policy::DenyOption => {
+                        event.outcome = "denied";
+                        self.audit.record(event);
+                        *handle = Handle::Denied {
+                            command: request.command,
+                        };
                         return Err(Error::new(EPERM));
                     }
-                    Policy::Authenticate => {
-                        let password = validate_utf8(buf)?;
-                        if user.verify_passwd(&password) {
-                            *handle = Handle::AwaitingContextFd
-                        } else {
-                            *handle = Handle::AwaitingPassword { uid };
-                            return Err(Error::new(EPERM));
+                    Policy::Allow { restrictions } => {
+                        self.audit.record(event);
+                        Handle::granted(uid, target, request, restrictions)
+                    }
+                    Policy::Authenticate {
+                        timestamp_timeout,
+                        restrictions,
+                    } => {
+                        let cached = session
+                            .and_then(|session| self.tickets.get_mut(&(uid, session)))
+                            .filter(|authenticated| authenticated.elapsed() < timestamp_timeout);
+                        match cached {
+                            Some(authenticated) => {
+                                *authenticated = Instant::now();
+                                self.audit.record(event);
+                                Handle::granted(uid, target, request, restrictions)
+                            }
+                            None => Handle::AwaitingPassword {
+                                uid,
+                                session,
+                                request,
+                                target,
+                                restrictions,
+                            },
                         }
                     }
+                };
+                *handle = next;
+            }
+            Handle::AwaitingPassword {
+                uid,
+                session,
+                request,
+                target,
+                restrictions,
+            } => {
+                if let Err(err) = self.throttle.check(uid) {
+                    self.audit.record(AuditEvent {
+                        service: "sudo",
+                        uid,
+                        target: target.map(|target| target.uid),
+                        command: target.map(|_| &*request.command),
+                        tty: request.tty.as_deref(),
+                        outcome: "throttled",
+                    });
+                    *handle = Handle::AwaitingPassword {
+                        uid,
+                        session,
+                        request,
+                        target,
+                        restrictions,
+                    };
+                    return Err(err);
+                }
+
+                let verified = match validate_utf8(buf)
+                    .and_then(|password| verify_password(uid as usize, password))
+                {
+                    Ok(verified) => verified,
+                    Err(err) => {
+                        *handle = Handle::AwaitingPassword {
+                            uid,
+                            session,
+                            request,
+                            target,
+                            restrictions,
+                        };
+                        return Err(err);
+                    }
+                };
+                self.throttle.record(uid, verified);
+                self.audit.record(AuditEvent {
+                    service: "sudo",
+                    uid,
+                    target: target.map(|target| target.uid),
+                    command: target.map(|_| &*request.command),
+                    tty: request.tty.as_deref(),
+                    outcome: if verified { "success" } else { "failure" },
+                });
+                if verified {
+                    if let Some(session) = session {
+                        // Expired tickets are dropped here, they would pile up
+                        // otherwise
+                        let timeout = self.cache.sudoers().map_or(Duration::ZERO, |sudoers| {
+                            sudoers.defaults.timestamp_duration()
+                        });
+                        self.tickets
+                            .retain(|_, authenticated| authenticated.elapsed() < timeout);
+                        self.tickets.insert((uid, session), Instant::now());
+                    }
+                    *handle = Handle::granted(uid, target, request, restrictions)
+                } else {
+                    *handle = Handle::AwaitingPassword {
+                        uid,
+                        session,
+                        request,
+                        target,
+                        restrictions,
+                    };
+                    return Err(Error::new(EPERM));
                 }
             }
-            Handle::AwaitingRootPassword => {
-                let users = AllUsers::authenticator(Config::default()).unwrap_or_exit(1);
-                let user = users.get_by_id(0).unwrap_or_exit(1);
+            Handle::AwaitingRootPassword { uid, tty } => {
+                if let Err(err) = self.throttle.check(uid) {
+                    self.audit.record(AuditEvent {
+                        service: "su",
+                        uid,
+                        target: Some(Target::ROOT.uid),
+                        command: None,
+                        tty: tty.as_deref(),
+                        outcome: "throttled",
+                    });
+                    *handle = Handle::AwaitingRootPassword { uid, tty };
+                    return Err(err);
+                }
 
-                let password = validate_utf8(buf)?;
-                if user.verify_passwd(&password) {
-                    *handle = Handle::AwaitingContextFd
+                let verified =
+                    match validate_utf8(buf).and_then(|password| verify_password(0, password)) {
+                        Ok(verified) => verified,
+                        Err(err) => {
+                            *handle = Handle::AwaitingRootPassword { uid, tty };
+                            return Err(err);
+                        }
+                    };
+                self.throttle.record(uid, verified);
+                self.audit.record(AuditEvent {
+                    service: "su",
+                    uid,
+                    target: Some(Target::ROOT.uid),
+                    command: None,
+                    tty: tty.as_deref(),
+                    outcome: if verified { "success" } else { "failure" },
+                });
+                if verified {
+                    *handle = Handle::AwaitingContextFd {
+                        target: Target::ROOT,
+                    }
                 } else {
-                    *handle = Handle::AwaitingRootPassword;
+                    *handle = Handle::AwaitingRootPassword { uid, tty };
                     return Err(Error::new(EPERM));
                 }
             }
-            Handle::AwaitingContextFd => {
-                *handle = Handle::AwaitingContextFd;
+            Handle::AwaitingContextFd { target } => {
+                *handle = Handle::AwaitingContextFd { target };
                 return Err(Error::new(EINVAL));
             }
+            Handle::AwaitingFds {
+                uid,
+                target,
+                request,
+                restrictions,
+                fds,
+            } => {
+                *handle = Handle::AwaitingFds {
+                    uid,
+                    target,
+                    request,
+                    restrictions,
+                    fds,
+                };
+                return Err(Error::new(EINVAL));
+            }
+            Handle::Running { child } => {
+                let signal = match buf {
+                    &[signal] if FORWARDED_SIGNALS.contains(&(signal as libc::c_int)) => signal,
+                    _ => {
+                        *handle = Handle::Running { child };
+                        return Err(Error::new(EINVAL));
+                    }
+                };
+                {
+                    // Only while it was not reaped, see `wait_command`
+                    let mut child = child.lock().unwrap();
+                    if let Ok(None) = child.try_wait() {
+                        unsafe { libc::kill(-(child.id() as libc::pid_t), signal as libc::c_int) };
+                    }
+                }
+                *handle = Handle::Running { child };
+            }
+            Handle::Validated => {
+                *handle = Handle::Validated;
+                return Err(Error::new(EINVAL));
+            }
+            Handle::Editing { uid, target, files } => {
+                *handle = Handle::Editing { uid, target, files };
+                return Err(Error::new(EINVAL));
+            }
+            Handle::Denied { command } => {
+                *handle = Handle::Denied { command };
+                return Err(Error::new(EACCES));
+            }
+
+            Handle::Invalidate { uid, session } => {
+                match buf {
+                    b"all" => self.tickets.retain(|&(ticket_uid, _), _| ticket_uid != uid),
+                    b"session" => {
+                        if let Some(session) = session {
+                            self.tickets.remove(&(uid, session));
+                        }
+                    }
+                    _ => {
+                        *handle = Handle::Invalidate { uid, session };
+                        return Err(Error::new(EINVAL));
+                    }
+                }
+                *handle = Handle::Invalidate { uid, session };
+            }
+            Handle::List { uid, text, pos } => {
+                // Check a single command, see `sudo -l COMMAND`
+                let mut request = parse_request(buf)?;
+                if request.validate {
+                    *handle = Handle::List { uid, text, pos };
+                    return Err(Error::new(EBADMSG));
+                }
+                let sudoers = self.cache.sudoers()?;
+                let accounts = self.cache.accounts()?;
+                resolve_request(&sudoers, &mut request)?;
+                match policy_for_user(&sudoers, &accounts, uid, &request)? {
+                    Policy::Deny | Policy::DenyOption => {
+                        *handle = Handle::List { uid, text, pos };
+                        return Err(Error::new(EACCES));
+                    }
+                    Policy::Allow { .. } | Policy::Authenticate { .. } => {
+                        *handle = Handle::List {
+                            uid,
+                            text: request.command.join(" ") + "\n",
+                            pos: 0,
+                        };
+                    }
+                }
+            }
 
-            Handle::AwaitingPasswordForPasswd { uid } => {
-                let users =
-                    AllUsers::authenticator(Config::default()).map_err(|_| Error::new(ENOLCK))?;
-                let user = users.get_by_id(uid as usize).ok_or(Error::new(EEXIST))?;
+            Handle::Reload => {
+                *handle = Handle::Reload;
+                self.cache.reload()?;
+            }
 
-                let password = validate_utf8(buf)?;
-                if user.verify_passwd(&password) {
+            Handle::AwaitingPasswordForPasswd { uid, tty } => {
+                if let Err(err) = self.throttle.check(uid) {
+                    self.audit.record(AuditEvent {
+                        service: "passwd",
+                        uid,
+                        target: Some(uid),
+                        command: None,
+                        tty: tty.as_deref(),
+                        outcome: "throttled",
+                    });
+                    *handle = Handle::AwaitingPasswordForPasswd { uid, tty };
+                    return Err(err);
+                }
+
+                let verified = match validate_utf8(buf)
+                    .and_then(|password| verify_password(uid as usize, password))
+                {
+                    Ok(verified) => verified,
+                    Err(err) => {
+                        *handle = Handle::AwaitingPasswordForPasswd { uid, tty };
+                        return Err(err);
+                    }
+                };
+                self.throttle.record(uid, verified);
+                self.audit.record(AuditEvent {
+                    service: "passwd",
+                    uid,
+                    target: Some(uid),
+                    command: None,
+                    tty: tty.as_deref(),
+                    outcome: if verified { "success" } else { "failure" },
+                });
+                if verified {
                     *handle = Handle::AwaitingNewPassword { uid }
                 } else {
-                    *handle = Handle::AwaitingPasswordForPasswd { uid };
+                    *handle = Handle::AwaitingPasswordForPasswd { uid, tty };
                     return Err(Error::new(EPERM));
                 }
             }
             Handle::AwaitingNewPassword { uid } => {
+                let new_password = validate_utf8(buf)?;
                 let mut users = AllUsers::authenticator(Config::default().writeable(true))
                     .map_err(|_| Error::new(ENOLCK))?;
-                let user = users
-                    .get_mut_by_id(uid as usize)
-                    .ok_or(Error::new(EEXIST))?;
+                let user = users.get_mut_by_id(uid as usize).ok_or(Error::new(ESRCH))?;
 
-                let new_password = validate_utf8(buf)?;
-                if user.set_passwd(&new_password).is_ok() {
+                if user.set_passwd(new_password).is_ok() {
                     users.save().map_err(|_| Error::new(ENOLCK))?;
                     *handle = Handle::Placeholder
                 } else {
@@ -316,15 +2279,226 @@ impl SchemeSync for Scheme {
         Ok(buf.len())
     }
 }
+
+/// The error of a failed I/O operation, as returned to the caller.
+fn io_error(err: io::Error) -> Error {
+    Error::new(err.raw_os_error().unwrap_or(EIO))
+}
+
+/// Duplicate `fd` to a number of at least `min`, so the command can move it to
+/// a lower number without closing another file descriptor it inherits.
+fn raise_fd(fd: OwnedFd, min: RawFd) -> Result<OwnedFd> {
+    match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min) } {
+        -1 => Err(io_error(io::Error::last_os_error())),
+        raised => Ok(unsafe { OwnedFd::from_raw_fd(raised) }),
+    }
+}
+
+/// The namespace of a command, holding the schemes `restrictions` limit it to.
+/// Without such a limit, it holds every scheme of the daemon and `user_ns`,
+/// the namespace of the caller, as `USER_NS_SCHEME`.
+fn command_namespace(
+    restrictions: &Restrictions,
+    user_ns: Option<OwnedFd>,
+) -> Result<libredox::Fd> {
+    let (schemes, user_ns) = match (&restrictions.schemes, user_ns) {
+        (Some(schemes), _) => (schemes.clone(), None),
+        (None, Some(user_ns)) => {
+            let schemes = fs::read_dir("/scheme")
+                .map_err(io_error)?
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|scheme| scheme != USER_NS_SCHEME)
+                .collect();
+            (schemes, Some(user_ns))
+        }
+        (None, None) => return Err(Error::new(EINVAL)),
+    };
+    let names: Vec<ioslice::IoSlice> = schemes
+        .iter()
+        .map(|scheme| ioslice::IoSlice::new(scheme.as_bytes()))
+        .collect();
+    let ns = libredox::call::mkns(&names).map(libredox::Fd::new)?;
+    if let Some(user_ns) = user_ns {
+        // Moved into the namespace
+        let user_ns = user_ns.into_raw_fd() as usize;
+        libredox::call::register_scheme_to_ns(ns.raw(), USER_NS_SCHEME, user_ns)?;
+    }
+    Ok(ns)
+}
+
 impl Scheme {
+    /// Run the command of the granted `request` of the caller `uid`, which
+    /// inherits `fds`, the standard input, output and error of the caller
+    /// followed by its other file descriptors below `close_from` and the
+    /// namespace of the caller unless `restrictions` limit the schemes.
+    ///
+    /// Returns the command and a pipe the exit status of sudo is written to
+    /// once it exited, see `wait_command`.
+    fn run(
+        &mut self,
+        uid: u32,
+        target: Target,
+        request: &Request,
+        restrictions: &Restrictions,
+        fds: Vec<OwnedFd>,
+    ) -> Result<(Arc<Mutex<Child>>, io::PipeReader)> {
+        let options = &request.options;
+        let first = options.close_from;
+        let inherited = usize::try_from(first)
+            .ok()
+            .filter(|_| (CLOSE_FROM..=open_max()).contains(&first))
+            .ok_or(Error::new(EINVAL))?;
+        let expected = inherited + restrictions.schemes.is_none() as usize;
+        if fds.len() != expected || (options.background && restrictions.log_io) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let sudoers = self.cache.sudoers()?;
+        let accounts = self.cache.accounts()?;
+        let account = accounts.by_name(&request.user)?;
+        let caller = accounts.by_id(uid as usize)?;
+        let cwd = if may_search(&request.cwd, caller, &accounts) {
+            &*request.cwd
+        } else {
+            "/"
+        };
+        let env = command_env(
+            &sudoers.defaults,
+            request.env.clone(),
+            request.preserve_env.as_deref(),
+            options.login,
+        );
+        let mut command = command(
+            &request.command,
+            account,
+            target.gid as usize,
+            env,
+            options.login,
+            Some(cwd),
+            restrictions,
+        );
+
+        let mut fds = fds.into_iter();
+        let [stdin, stdout, stderr] = [(); 3].map(|_| fds.next().unwrap());
+        // Moved to their number in the command
+        let moved = (CLOSE_FROM..first)
+            .zip(fds.by_ref())
+            .map(|(number, fd)| Ok((number, raise_fd(fd, first)?)));
+        let moved: Vec<(RawFd, OwnedFd)> = moved.collect::<Result<_>>()?;
+        let ns = command_namespace(restrictions, fds.next())?;
+        let background = options.background;
+        unsafe {
+            command.pre_exec(move || {
+                for (number, fd) in &moved {
+                    if libc::dup2(fd.as_raw_fd(), *number) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                close_from(first);
+                libredox::call::setns(ns.raw())?;
+                if background {
+                    libc::setsid();
+                }
+                Ok(())
+            })
+        };
+
+        // Messages of sudo itself go to the standard error of the caller
+        let mut messages = File::from(stderr.try_clone().map_err(io_error)?);
+        if cwd != request.cwd
+            && !request.cwd.is_empty()
+            && !options.login
+            && restrictions.chdir.is_none()
+        {
+            let _ = writeln!(
+                messages,
+                "sudo: {} cannot be searched, running {} in /",
+                request.cwd, request.command[0]
+            );
+        }
+        let recording = if restrictions.log_io {
+            let info = Info {
+                time: unix_time(),
+                uid,
+                target: target.uid,
+                command: request.command.clone(),
+            };
+            let iolog_dir = &sudoers.defaults.iolog_dir;
+            let log = iolog::create(iolog_dir, &info).map_err(|err| {
+                eprintln!("sudo: failed to record a session in {}: {}", iolog_dir, err);
+                Error::new(EIO)
+            })?;
+            let size = options.terminal_size.unwrap_or((80, 30));
+            let recording = Recording::new(&mut command, log, stdin, File::from(stdout), size)
+                .map_err(io_error)?;
+            Some(recording)
+        } else {
+            if background {
+                command.stdin(Stdio::null());
+            } else {
+                command.stdin(stdin);
+            }
+            command.stdout(stdout).stderr(stderr);
+            None
+        };
+
+        // Either way the command is not in the session of the caller and has
+        // no controlling terminal, unlike the one sudo executes for root
+        let child = if background {
+            // A session of its own, see `sudo -b`
+            command.spawn()
+        } else {
+            spawn_in_group(command, recording.is_some())
+        };
+        let child = Arc::new(Mutex::new(child.map_err(io_error)?));
+
+        let (status, mut status_writer) = io::pipe().map_err(io_error)?;
+        let waited = child.clone();
+        let name = request.command[0].clone();
+        let timeout = restrictions.timeout;
+        thread::spawn(move || {
+            let status = wait_command(&waited, &name, timeout, &mut messages);
+            if let Some(recording) = recording {
+                recording.finish();
+            }
+            // Nobody reads it if the command runs in the background
+            let _ = write!(status_writer, "{}", status);
+        });
+        Ok((child, status))
+    }
+
     fn on_close(&mut self, id: usize) {
         self.handles.remove(&id);
+        self.reply_pos.remove(&id);
     }
 
     fn on_sendfd(&mut self, socket: &Socket, req: &SendFdRequest) -> Result<usize> {
         let handle = self.handles.get_mut(&req.id()).ok_or(Error::new(EBADF))?;
+        if let Handle::AwaitingFds { request, fds, .. } = handle {
+            // The namespace of the caller may follow those it inherits
+            let first = request.options.close_from;
+            if first > open_max() || fds.len() > first as usize {
+                return Err(Error::new(EINVAL));
+            }
+            let mut fd = usize::MAX;
+            req.obtain_fd(
+                socket,
+                FobtainFdFlags::CLOEXEC,
+                std::slice::from_mut(&mut fd),
+            )?;
+            fds.push(unsafe { OwnedFd::from_raw_fd(fd as RawFd) });
+            return Ok(0);
+        }
+
         match std::mem::replace(handle, Handle::Placeholder) {
-            Handle::AwaitingContextFd => {
+            Handle::AwaitingContextFd { target } => {
+                // Prepare the namespace first, to not leave the caller
+                // elevated without one
+                let ns = libredox::call::getns()
+                    .and_then(|ns| libredox::call::dup(ns, b""))
+                    .map_err(|err| Error::new(err.errno()))?;
+                let ns = libredox::Fd::new(ns);
+
                 let mut proc_fd = usize::MAX;
                 req.obtain_fd(
                     socket,
@@ -333,8 +2507,8 @@ impl Scheme {
                 )?;
                 let proc_fd = unsafe { OwnedFd::from_raw_fd(proc_fd as RawFd) };
 
-                let [ruid, euid, suid] = [0, 0, 0];
-                let [rgid, egid, sgid] = [0, 0, 0];
+                let [ruid, euid, suid] = [target.uid; 3];
+                let [rgid, egid, sgid] = [target.gid; 3];
                 let mut payload = [0; size_of::<u32>() * 6];
                 plain::slice_from_mut_bytes(&mut payload)
                     .unwrap()
@@ -346,14 +2520,12 @@ impl Scheme {
                     CallFlags::empty(),
                     &[ProcCall::SetResugid as u64],
                 ) {
-                    eprintln!("failed to setresugid: {err}");
+                    // Never hand out the namespace to a caller left unprivileged
+                    eprintln!("sudo: failed to setresugid: {err}");
+                    return Err(err);
                 }
 
-                *handle = Handle::AwaitingNamespaceFetch {
-                    ns: libredox::Fd::new(
-                        libredox::call::dup(libredox::call::getns().unwrap(), b"").unwrap(),
-                    ),
-                };
+                *handle = Handle::AwaitingNamespaceFetch { ns };
             }
             old => {
                 *handle = old;
@@ -365,13 +2537,23 @@ impl Scheme {
 }
 
 fn daemon_main() -> ! {
-    // TODO: Linux kernel audit-like logging?
+    let mut cache = Cache::default();
+    let audit_log = match cache.sudoers() {
+        Ok(sudoers) => sudoers.defaults.audit_log.clone(),
+        Err(_) => Defaults::default().audit_log,
+    };
+
     let socket = Socket::create().expect("failed to open scheme socket");
 
     let mut state = SchemeState::new();
     let mut scheme = Scheme {
         next_fd: 1,
         handles: HashMap::new(),
+        tickets: HashMap::new(),
+        reply_pos: HashMap::new(),
+        throttle: Throttle::default(),
+        audit: AuditLog::open(&audit_log),
+        cache,
     };
 
     register_sync_scheme(&socket, "sudo", &mut scheme)
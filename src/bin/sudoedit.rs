@@ -0,0 +1,25 @@
+use std::env;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, exit};
+
+const _MAN_PAGE: &'static str = /* @MANSTART{sudoedit} */
+    r#"
+NAME
+    sudoedit - edit files as another user
+
+SYNOPSIS
+    sudoedit [ -u user ] [ -g group ] file...
+
+DESCRIPTION
+    The sudoedit utility runs sudo -e with its arguments, editing the given
+    files as the target user, see sudo(8).
+"#; /* @MANEND */
+
+fn main() {
+    let err = Command::new("sudo")
+        .arg("--edit")
+        .args(env::args_os().skip(1))
+        .exec();
+    eprintln!("sudoedit: failed to execute sudo: {}", err);
+    exit(1);
+}
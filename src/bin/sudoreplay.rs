@@ -0,0 +1,147 @@
+#[macro_use]
+extern crate clap;
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+use extra::option::OptionalExt;
+use redox_users::{All, AllUsers, Config};
+use userutils::iolog::{IOLOG_DIR, Session, Stream};
+
+const _MAN_PAGE: &'static str = /* @MANSTART{sudoreplay} */
+    r#"
+NAME
+    sudoreplay - play back the sessions recorded by sudo
+
+SYNOPSIS
+    sudoreplay [ -d DIR ] [ -s SPEED ] [ -m MAX_WAIT ] SESSION
+    sudoreplay -l [ -d DIR ]
+    sudoreplay [ -h | --help ]
+
+DESCRIPTION
+    The sudoreplay utility writes the output of a session recorded by sudo(8)
+    to the terminal, with the timing it was recorded with. SESSION is the
+    name of the directory the session was recorded to, as listed by -l, or
+    its path.
+
+    Sessions are recorded for the commands of the rules of /etc/sudoers.toml
+    with log_io = true, see sudo(8).
+
+OPTIONS
+    -h, --help
+        Display this help and exit.
+
+    -l, --list
+        List the recorded sessions, oldest first, with the user who ran sudo,
+        the user the command ran as and the command.
+
+    -d, --directory DIR
+        Read the sessions from DIR instead of /var/log/sudo-io.
+
+    -s, --speed SPEED
+        Play the session back SPEED times faster, 2 halving every delay.
+
+    -m, --max-wait MAX_WAIT
+        Wait at most MAX_WAIT seconds between two writes.
+"#; /* @MANEND */
+
+fn main() {
+    let args = clap_app!(sudoreplay =>
+        (about: "Play back the sessions recorded by sudo")
+        (@arg LIST: -l --list conflicts_with[SESSION] "List the recorded sessions")
+        (@arg DIRECTORY: -d --directory +takes_value "Directory the sessions are recorded in")
+        (@arg SPEED: -s --speed +takes_value "Speed up the session by this factor")
+        (@arg MAX_WAIT: -m --("max-wait") +takes_value "Maximum seconds to wait between writes")
+        (@arg SESSION: required_unless[LIST] "Session to play back")
+    )
+    .get_matches();
+
+    let dir = args.value_of("DIRECTORY").unwrap_or(IOLOG_DIR);
+
+    if args.is_present("LIST") {
+        list_sessions(dir);
+        return;
+    }
+
+    let number = |name: &str| {
+        args.value_of(name).map(|value| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| *value > 0.0)
+                .unwrap_or_else(|| {
+                    eprintln!("sudoreplay: invalid number {}", value);
+                    exit(1);
+                })
+        })
+    };
+    let speed = number("SPEED").unwrap_or(1.0);
+    // Waits too long to fit in a Duration are only bounded by MAX_WAIT
+    let duration = |secs: f64| Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX);
+    let max_wait = number("MAX_WAIT").map(duration);
+
+    let session = args.value_of("SESSION").unwrap();
+    let path = if session.contains('/') {
+        PathBuf::from(session)
+    } else {
+        Path::new(dir).join(session)
+    };
+    let mut session = Session::open(&path).unwrap_or_else(|err| {
+        eprintln!("sudoreplay: failed to open {}: {}", path.display(), err);
+        exit(1);
+    });
+
+    let mut stdout = io::stdout();
+    loop {
+        let record = match session.next_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("sudoreplay: failed to read {}: {}", path.display(), err);
+                exit(1);
+            }
+        };
+        if record.stream != Stream::Output {
+            // Echoed by the terminal of the command if it was visible
+            continue;
+        }
+
+        let delay = duration(record.delay.as_secs_f64() / speed);
+        thread::sleep(max_wait.map_or(delay, |max_wait| delay.min(max_wait)));
+        stdout.write_all(&record.data).unwrap_or_exit(1);
+        stdout.flush().unwrap_or_exit(1);
+    }
+}
+
+fn list_sessions(dir: &str) {
+    let sessions = Session::list(dir).unwrap_or_else(|err| {
+        eprintln!("sudoreplay: failed to read {}: {}", dir, err);
+        exit(1);
+    });
+
+    // Numeric ids are shown for users which no longer exist
+    let users = AllUsers::basic(Config::default()).ok();
+    let name = |uid: u32| {
+        users
+            .as_ref()
+            .and_then(|users| users.get_by_id(uid as usize))
+            .map_or(uid.to_string(), |user| user.user.clone())
+    };
+
+    for path in sessions {
+        let Ok(session) = Session::open(&path) else {
+            continue;
+        };
+        let info = &session.info;
+        println!(
+            "{}: {} -> {}: {}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            name(info.uid),
+            name(info.target),
+            info.command.join(" ")
+        );
+    }
+}
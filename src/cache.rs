@@ -0,0 +1,188 @@
+//! Copies of the policy and of the user and group databases kept by the sudo
+//! daemon between requests, loaded again once their files are modified.
+
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use redox_users::{All, AllGroups, AllUsers, Config};
+use syscall::error::{EACCES, EINVAL, ENOLCK, ESRCH, Error, Result};
+
+use crate::AllGroupsExt;
+use crate::sudoers::{SUDOERS_FILE, Sudoers};
+
+pub const PASSWD_FILE: &str = "/etc/passwd";
+pub const GROUP_FILE: &str = "/etc/group";
+
+/// A user as seen by the policy.
+pub struct Account {
+    pub name: String,
+    pub uid: usize,
+    pub gid: usize,
+    /// Names of the groups of the user, including their primary group
+    pub groups: Vec<String>,
+    pub home: String,
+    pub shell: String,
+}
+
+/// A copy of the user and group databases. Unlike `AllUsers` and `AllGroups`
+/// it does not keep their files locked, so it can be kept between requests.
+pub struct Accounts {
+    users: Vec<Account>,
+    groups: HashMap<String, usize>,
+}
+
+impl Accounts {
+    fn load() -> Result<Accounts> {
+        let users = AllUsers::basic(Config::default()).map_err(|_| Error::new(ENOLCK))?;
+        let groups = AllGroups::new(Config::default()).map_err(|_| Error::new(ENOLCK))?;
+
+        Ok(Accounts {
+            users: users
+                .iter()
+                .map(|user| Account {
+                    name: user.user.clone(),
+                    uid: user.uid,
+                    gid: user.gid,
+                    groups: groups.member_groups(&user.user, user.gid),
+                    home: user.home.clone(),
+                    shell: user.shell.clone(),
+                })
+                .collect(),
+            groups: groups
+                .iter()
+                .map(|group| (group.group.clone(), group.gid))
+                .collect(),
+        })
+    }
+
+    pub fn by_id(&self, uid: usize) -> Result<&Account> {
+        self.users
+            .iter()
+            .find(|user| user.uid == uid)
+            .ok_or(Error::new(ESRCH))
+    }
+
+    pub fn by_name(&self, name: &str) -> Result<&Account> {
+        self.users
+            .iter()
+            .find(|user| user.name == name)
+            .ok_or(Error::new(ESRCH))
+    }
+
+    pub fn group_id(&self, name: &str) -> Result<usize> {
+        self.groups.get(name).copied().ok_or(Error::new(ESRCH))
+    }
+}
+
+/// Modification times of `files`, `None` for those which cannot be read.
+fn modification_times(files: &[&str]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            fs::metadata(file)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// The policy and the accounts requests are checked against, along with the
+/// modification times of the files they were loaded from. Both are loaded
+/// again once these files are modified, or when root writes to
+/// `/scheme/sudo/reload`. Passwords are always checked against the user
+/// database itself.
+#[derive(Default)]
+pub struct Cache {
+    sudoers: Option<(Rc<Sudoers>, Vec<Option<SystemTime>>)>,
+    accounts: Option<(Rc<Accounts>, Vec<Option<SystemTime>>)>,
+}
+
+impl Cache {
+    /// The current policy. If it fails to load, the last one loaded stays in
+    /// effect, and without one every request is refused.
+    pub fn sudoers(&mut self) -> Result<Rc<Sudoers>> {
+        let mtimes = modification_times(&[SUDOERS_FILE]);
+        let outdated = match &self.sudoers {
+            Some((_, loaded)) => *loaded != mtimes,
+            None => true,
+        };
+        if outdated {
+            // Reported by `load_sudoers`
+            let _ = self.load_sudoers();
+        }
+        match &self.sudoers {
+            Some((sudoers, _)) => Ok(sudoers.clone()),
+            None => Err(Error::new(EACCES)),
+        }
+    }
+
+    /// Load the policy, refusing it with `EINVAL` if it is invalid or
+    /// insecure.
+    fn load_sudoers(&mut self) -> Result<()> {
+        let mtimes = modification_times(&[SUDOERS_FILE]);
+        match Sudoers::load() {
+            Ok(sudoers) => {
+                self.sudoers = Some((Rc::new(sudoers), mtimes));
+                Ok(())
+            }
+            Err(err) => {
+                eprintln!("sudo: failed to load {}: {}", SUDOERS_FILE, err);
+                if let Some((_, loaded)) = &mut self.sudoers {
+                    // Not retried until the file is modified again
+                    eprintln!("sudo: keeping the previous policy");
+                    *loaded = mtimes;
+                }
+                Err(Error::new(EINVAL))
+            }
+        }
+    }
+
+    pub fn accounts(&mut self) -> Result<Rc<Accounts>> {
+        let mtimes = modification_times(&[PASSWD_FILE, GROUP_FILE]);
+        match &self.accounts {
+            Some((accounts, loaded)) if *loaded == mtimes => return Ok(accounts.clone()),
+            _ => (),
+        }
+        let accounts = Rc::new(Accounts::load()?);
+        self.accounts = Some((accounts.clone(), mtimes));
+        Ok(accounts)
+    }
+
+    /// Load everything again, see `/scheme/sudo/reload`.
+    pub fn reload(&mut self) -> Result<()> {
+        self.accounts = None;
+        self.load_sudoers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn modification_times_change_with_files() {
+        let path = env::temp_dir().join(format!("cache-test-{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(modification_times(&[path_str]), [None]);
+
+        fs::write(&path, "").unwrap();
+        let created = modification_times(&[path_str]);
+        assert!(created[0].is_some());
+        assert_eq!(modification_times(&[path_str]), created);
+
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(created[0].unwrap() + Duration::from_secs(1))
+            .unwrap();
+        assert_ne!(modification_times(&[path_str]), created);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(modification_times(&[path_str]), [None]);
+    }
+}
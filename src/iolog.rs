@@ -0,0 +1,228 @@
+//! Recordings of the terminal sessions of commands run by sudo, see the
+//! `log_io` settings of [`crate::sudoers`].
+//!
+//! Every session is recorded to a directory of its own in [`IOLOG_DIR`],
+//! named after the time it started and the user who ran sudo. It holds:
+//!
+//! - `info`: who ran which command and when, see [`Info`]
+//! - `log`: what was typed on and written to the terminal of the command, as
+//!   a sequence of records. A record is a line `STREAM DELAY COUNT`, where
+//!   `STREAM` is `i` for input or `o` for output and `DELAY` the number of
+//!   milliseconds since the previous record, followed by `COUNT` bytes of
+//!   data.
+//!
+//! `sudoreplay` lists and plays back the recorded sessions.
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+pub const IOLOG_DIR: &'static str = "/var/log/sudo-io";
+
+const INFO_FILE: &'static str = "info";
+const LOG_FILE: &'static str = "log";
+/// Largest record played back. sudo records at most 4096 bytes at a time, a
+/// larger count comes from a corrupted log.
+const MAX_RECORD_SIZE: usize = 64 * 1024;
+
+/// Describes a recorded session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Info {
+    /// Seconds since the Unix epoch at which the session started.
+    pub time: u64,
+    /// User who ran sudo.
+    pub uid: u32,
+    /// User the command was run as.
+    pub target: u32,
+    /// The command, starting with the absolute path of its executable.
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    /// What was typed on the terminal.
+    Input,
+    /// What the command wrote to the terminal.
+    Output,
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub stream: Stream,
+    /// Time elapsed since the previous record.
+    pub delay: Duration,
+    pub data: Vec<u8>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Create the directory of a new session in `dir`, only readable by its
+/// owner, and return the log to record the session to.
+pub fn create(dir: &str, info: &Info) -> io::Result<File> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let mut n = 0;
+    let path = loop {
+        let path = Path::new(dir).join(format!("{}-{}-{}", info.time, info.uid, n));
+        match DirBuilder::new().mode(0o700).create(&path) {
+            Ok(()) => break path,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(err) => return Err(err),
+        }
+    };
+
+    let info = toml::to_string(info).map_err(|_| invalid_data("invalid session info"))?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true).mode(0o600);
+    options
+        .open(path.join(INFO_FILE))?
+        .write_all(info.as_bytes())?;
+
+    options.append(true).open(path.join(LOG_FILE))
+}
+
+/// Appends the records of a session to its log.
+pub struct Recorder {
+    log: File,
+    last: Instant,
+}
+
+impl Recorder {
+    pub fn new(log: File) -> Recorder {
+        Recorder {
+            log,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, stream: Stream, data: &[u8]) -> io::Result<()> {
+        let now = Instant::now();
+        let delay = now - self.last;
+        self.last = now;
+
+        let stream = match stream {
+            Stream::Input => 'i',
+            Stream::Output => 'o',
+        };
+        let mut record = format!("{} {} {}\n", stream, delay.as_millis(), data.len()).into_bytes();
+        record.extend_from_slice(data);
+        // A single write keeps the header and its data together
+        self.log.write_all(&record)
+    }
+}
+
+/// A recorded session being played back.
+pub struct Session {
+    pub info: Info,
+    log: BufReader<File>,
+}
+
+impl Session {
+    /// Open the session recorded to the directory `path`.
+    pub fn open(path: &Path) -> io::Result<Session> {
+        let info = fs::read_to_string(path.join(INFO_FILE))?;
+        let info = toml::from_str(&info).map_err(|_| invalid_data("invalid session info"))?;
+        let log = BufReader::new(File::open(path.join(LOG_FILE))?);
+        Ok(Session { info, log })
+    }
+
+    /// The directories of the sessions recorded in `dir`, oldest first.
+    pub fn list(dir: &str) -> io::Result<Vec<PathBuf>> {
+        let mut sessions = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.join(INFO_FILE).is_file() {
+                sessions.push(path);
+            }
+        }
+        // Sorting by the time the sessions started, which their names
+        // begin with
+        sessions.sort_by_key(|path| {
+            let name = path.file_name().and_then(|name| name.to_str());
+            let time = name.and_then(|name| name.split('-').next()?.parse::<u64>().ok());
+            (time, path.clone())
+        });
+        Ok(sessions)
+    }
+
+    /// Read the next record, `None` at the end of the log.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = String::new();
+        if self.log.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let mut parts = header.split_whitespace();
+        let stream = match parts.next() {
+            Some("i") => Stream::Input,
+            Some("o") => Stream::Output,
+            _ => return Err(invalid_data("invalid record stream")),
+        };
+        let mut number = || {
+            parts
+                .next()
+                .and_then(|part| part.parse::<u64>().ok())
+                .ok_or_else(|| invalid_data("invalid record header"))
+        };
+        let delay = Duration::from_millis(number()?);
+        let count = number()? as usize;
+        if count > MAX_RECORD_SIZE {
+            return Err(invalid_data("record too large"));
+        }
+
+        let mut data = vec![0; count];
+        self.log.read_exact(&mut data)?;
+        Ok(Some(Record {
+            stream,
+            delay,
+            data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn records_are_played_back() {
+        let dir = env::temp_dir().join(format!("iolog-test-{}", std::process::id()));
+        let dir_str = dir.to_str().unwrap();
+        let info = Info {
+            time: 0,
+            uid: 1000,
+            target: 0,
+            command: vec!["/usr/bin/id".to_string()],
+        };
+        let mut log = create(dir_str, &info).unwrap();
+        let mut recorder = Recorder::new(log.try_clone().unwrap());
+        recorder.record(Stream::Input, b"id\n").unwrap();
+        recorder.record(Stream::Output, b"0\n").unwrap();
+        writeln!(log, "o 0 {}", MAX_RECORD_SIZE + 1).unwrap();
+
+        let sessions = Session::list(dir_str).unwrap();
+        let mut session = Session::open(&sessions[0]).unwrap();
+        assert_eq!(session.info.command, info.command);
+        let record = session.next_record().unwrap().unwrap();
+        assert_eq!(
+            (record.stream, &record.data[..]),
+            (Stream::Input, &b"id\n"[..])
+        );
+        let record = session.next_record().unwrap().unwrap();
+        assert_eq!(
+            (record.stream, &record.data[..]),
+            (Stream::Output, &b"0\n"[..])
+        );
+        let err = session.next_record().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,15 +14,27 @@
 //! - `passwd`: Allows users to modify their passwords.
 //! - `su`: Allows users to substitute identity.
 //! - `sudo`: Enables users to execute a command as another user.
+//! - `sudoedit`: Edits files as another user, like `sudo -e`.
+//! - `sudoreplay`: Plays back the sessions recorded by `sudo`.
 //! - `whoami`: Display effective user ID.
 
+use std::env;
 use std::io::Result as IoResult;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use libredox::call::{fchown, open};
 use libredox::error::Result as SysResult;
 use libredox::flag::{O_CLOEXEC, O_CREAT, O_DIRECTORY};
 use redox_users::{All, AllGroups, Error, Result, User, auth};
 
+pub mod audit;
+pub mod cache;
+pub mod iolog;
+pub mod pty;
+pub mod recording;
+pub mod sudoers;
+pub mod throttle;
+
 const DEFAULT_MODE: u16 = 0o700;
 
 // Not the prettiest thing in the world, but some functionality here makes
@@ -30,6 +42,7 @@ const DEFAULT_MODE: u16 = 0o700;
 pub trait AllGroupsExt {
     fn add_user_to_groups(&mut self, login: &str, groups: Vec<&str>) -> Result<()>;
     fn remove_user_from_all_groups(&mut self, login: &str);
+    fn member_groups(&self, login: &str, gid: usize) -> Vec<String>;
 }
 
 impl AllGroupsExt for AllGroups {
@@ -54,6 +67,14 @@ impl AllGroupsExt for AllGroups {
             }
         }
     }
+
+    /// Names of the groups a user belongs to, including their primary group
+    fn member_groups(&self, login: &str, gid: usize) -> Vec<String> {
+        self.iter()
+            .filter(|group| group.gid == gid || group.users.iter().any(|name| name == login))
+            .map(|group| group.group.clone())
+            .collect()
+    }
 }
 
 /// Spawns a shell for the given `User`.
@@ -90,3 +111,20 @@ where
     fchown(fd, user.uid as u32, user.gid as u32)?;
     Ok(())
 }
+
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Opens the `su` or `passwd` service of the sudo daemon. The `TTY` of the
+/// caller is appended to the path, for the audit log of the daemon.
+pub fn open_sudo_service(service: &str) -> SysResult<usize> {
+    let tty = env::var("TTY")
+        .ok()
+        .filter(|tty| tty.starts_with('/'))
+        .unwrap_or_default();
+    open(format!("/scheme/sudo/{}{}", service, tty), O_CLOEXEC, 0)
+}
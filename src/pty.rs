@@ -0,0 +1,43 @@
+//! Pseudo terminals, used by `getty` for the sessions it starts and by
+//! `sudo` for the sessions it records.
+
+use core::ptr::slice_from_raw_parts;
+use std::os::unix::io::RawFd;
+
+use libc::{grantpt, ptsname, strlen, unlockpt};
+use libredox::call as redox;
+use libredox::error::Result;
+use libredox::flag;
+
+/// Open a new pseudo terminal of `columns` by `lines`, returning its
+/// non-blocking master and the path of its slave.
+///
+/// What is read from the master starts with a byte of flags, the first bit
+/// asking for the data to be flushed, followed by the output of the slave.
+pub fn getpty(columns: u16, lines: u16) -> Result<(RawFd, String)> {
+    let master = redox::open(
+        "/scheme/pty/ptmx",
+        flag::O_CLOEXEC | flag::O_RDWR | flag::O_CREAT | flag::O_NONBLOCK,
+        0,
+    )?;
+
+    if let Ok(winsize_fd) = redox::dup(master, b"winsize") {
+        let _ = redox::write(
+            winsize_fd,
+            &redox_termios::Winsize {
+                ws_row: lines,
+                ws_col: columns,
+            },
+        );
+        let _ = redox::close(winsize_fd);
+    }
+    let _ = unsafe { grantpt(master as RawFd) };
+    let _ = unsafe { unlockpt(master as RawFd) };
+
+    let name = unsafe { ptsname(master as RawFd) };
+    let count = unsafe { strlen(name) };
+    let buf = unsafe { &*slice_from_raw_parts(name.cast(), count) };
+    Ok((master as RawFd, unsafe {
+        String::from_utf8_unchecked(Vec::from(&buf[..count]))
+    }))
+}
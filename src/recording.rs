@@ -0,0 +1,142 @@
+//! Terminal sessions of the commands run by sudo with `log_io`, relayed
+//! between a pseudo terminal and the terminal of the caller while they are
+//! recorded, see [`crate::iolog`].
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use event::{EventFlags, RawEventQueue};
+use libredox::flag::O_CLOEXEC;
+
+use crate::iolog::{Recorder, Stream};
+use crate::pty::getpty;
+
+/// The pseudo terminal of a recorded command.
+struct Session {
+    master: OwnedFd,
+    /// Terminal of the caller
+    output: File,
+    recorder: Recorder,
+}
+
+impl Session {
+    /// Pass what the command wrote to its terminal on to the one of the
+    /// caller, until there is nothing left to read.
+    fn relay_output(&mut self) {
+        let mut packet = [0; 4096];
+        loop {
+            let count = match libredox::call::read(self.master.as_raw_fd() as usize, &mut packet) {
+                Ok(count) if count > 0 => count,
+                // Nothing left or the command exited
+                _ => return,
+            };
+            let data = &packet[1..count];
+            let _ = self.output.write_all(data);
+            let _ = self.output.flush();
+            self.record(Stream::Output, data);
+        }
+    }
+
+    fn relay_input(&mut self, data: &[u8]) {
+        let _ = libredox::call::write(self.master.as_raw_fd() as usize, data);
+        self.record(Stream::Input, data);
+    }
+
+    fn record(&mut self, stream: Stream, data: &[u8]) {
+        if let Err(err) = self.recorder.record(stream, data) {
+            eprintln!("sudo: failed to record the session: {}\r", err);
+        }
+    }
+}
+
+/// A recorded command, whose pseudo terminal is relayed to the terminal of
+/// the caller until `finish` is called.
+pub struct Recording {
+    session: Arc<Mutex<Session>>,
+    /// Written to stop the relay, see `relay_session`
+    stop: io::PipeWriter,
+    relay: thread::JoinHandle<()>,
+}
+
+impl Recording {
+    /// Make `command` run on a new pseudo terminal of `size` columns and
+    /// lines, recording its session to `log`. What is typed on `input` is
+    /// passed on to the command, what it writes is passed on to `output`.
+    pub fn new(
+        command: &mut Command,
+        log: File,
+        input: OwnedFd,
+        output: File,
+        (columns, lines): (u16, u16),
+    ) -> io::Result<Recording> {
+        let (master, pty) = getpty(columns, lines).map_err(io::Error::from)?;
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+        let open_slave = |flags| {
+            libredox::call::open(&pty, O_CLOEXEC | flags, 0)
+                .map(|fd| unsafe { Stdio::from_raw_fd(fd as RawFd) })
+                .map_err(io::Error::from)
+        };
+        command
+            .stdin(open_slave(libredox::flag::O_RDONLY)?)
+            .stdout(open_slave(libredox::flag::O_WRONLY)?)
+            .stderr(open_slave(libredox::flag::O_WRONLY)?)
+            .env("TTY", &pty);
+
+        let (stopped, stop) = io::pipe()?;
+        let session = Arc::new(Mutex::new(Session {
+            master,
+            output,
+            recorder: Recorder::new(log),
+        }));
+        let relayed = session.clone();
+        let relay = thread::spawn(move || relay_session(&relayed, input, stopped));
+        Ok(Recording {
+            session,
+            stop,
+            relay,
+        })
+    }
+
+    /// Stop relaying once the command exited, passing on what is left of its
+    /// output.
+    pub fn finish(mut self) {
+        let _ = self.stop.write_all(&[0]);
+        let _ = self.relay.join();
+        self.session.lock().unwrap().relay_output();
+    }
+}
+
+/// Relay the input and output of a recorded session as they come, until
+/// something is written to `stop`.
+fn relay_session(session: &Mutex<Session>, input: OwnedFd, stop: io::PipeReader) {
+    let master = session.lock().unwrap().master.as_raw_fd() as usize;
+    let [input_fd, stop_fd] = [input.as_raw_fd(), stop.as_raw_fd()].map(|fd| fd as usize);
+    let Ok(event_queue) = RawEventQueue::new() else {
+        return;
+    };
+    for fd in [input_fd, master, stop_fd] {
+        if event_queue.subscribe(fd, 0, EventFlags::READ).is_err() {
+            return;
+        }
+    }
+
+    for event in event_queue {
+        let Ok(event) = event else {
+            return;
+        };
+        if event.fd == stop_fd {
+            return;
+        } else if event.fd == input_fd {
+            let mut buf = [0; 4096];
+            if let Ok(count @ 1..) = libredox::call::read(input_fd, &mut buf) {
+                session.lock().unwrap().relay_input(&buf[..count]);
+            }
+        } else {
+            session.lock().unwrap().relay_output();
+        }
+    }
+}
@@ -0,0 +1,743 @@
+//! The sudo security policy, read from `/etc/sudoers.toml`.
+//!
+//! The policy is a list of rules. A rule names the users and groups it
+//! applies to, the users they may run commands as and the commands they may
+//! run:
+//!
+//! ```toml
+//! [defaults]
+//! timestamp_timeout = 5
+//! audit_log = "/scheme/log"
+//! env_keep = ["TERM", "TTY", "LANG", "LC_*"]
+//! secure_path = "/usr/bin"
+//!
+//! [[rule]]
+//! groups = ["sudo"]
+//! commands = ["ALL"]
+//!
+//! [[rule]]
+//! users = ["alice"]
+//! runas = ["root"]
+//! commands = ["/usr/bin/netctl restart", "/usr/bin/dmesg"]
+//!
+//! [[rule]]
+//! groups = ["build"]
+//! commands = ["/usr/bin/make install"]
+//! nopasswd = true
+//! setenv = true
+//! schemes = ["file", "pty", "null"]
+//! timeout = 3600
+//! chdir = "/home/build"
+//! umask = 0o022
+//!
+//! [[rule]]
+//! users = ["operator"]
+//! commands = ["ALL"]
+//! log_io = true
+//! ```
+//!
+//! A command is either `ALL`, an absolute path (any arguments allowed) or an
+//! absolute path followed by the exact arguments that must be passed. Files
+//! that may be edited with `sudo -e` are listed as `sudoedit /path/to/file`,
+//! naming at least one file. `runas` defaults to `root` and also accepts
+//! `ALL`, `runas_groups` lists the groups that may be requested as primary
+//! group and is empty by default. Setting
+//! `nopasswd = true` lets the commands of a rule run without asking for a
+//! password, `setenv = true` lets the caller keep its environment with
+//! `sudo -E` or `sudo --preserve-env=LIST`. If `schemes` is set, the commands
+//! get a namespace with only these schemes, like the ones `login` builds,
+//! instead of one with every scheme and the namespace of the caller as the
+//! `user` scheme. `timeout` is the number of seconds after
+//! which the commands are killed, `chdir` the directory they run in (`*`
+//! letting the caller choose it with `sudo --chdir`) and `umask` their file
+//! mode creation mask. The terminal sessions of the commands of a rule with
+//! `log_io = true` are recorded, see [`crate::iolog`].
+//!
+//! The first rule that matches a request is the one applied to it.
+//!
+//! The optional `[defaults]` table holds settings that are not tied to a
+//! rule. `timestamp_timeout` is the number of minutes for which a successful
+//! authentication is remembered per user and login session, `0` disabling
+//! this. `audit_log` is the file authentication attempts are appended to, an
+//! empty string disabling the audit log. `log_io` records the sessions of every
+//! command, including those run by root, in a directory of `iolog_dir`.
+//!
+//! Commands do not inherit the environment of the caller as is. With
+//! `env_reset` (the default) they only get the variables listed in
+//! `env_keep`, otherwise they get every variable not listed in `env_delete`.
+//! Both lists hold names, or prefixes followed by `*`. `PATH` is always set
+//! to `secure_path`, unless it is empty.
+//!
+//! Commands given by name are searched in `secure_path` rather than in the
+//! `PATH` of the caller, and must be given by path if it is empty. Relative
+//! paths like `./script` are refused unless `allow_relative_paths` is set.
+//!
+//! If the file does not exist, members of the `sudo` group may run any
+//! command as any user, which matches the behaviour of older versions.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+pub const SUDOERS_FILE: &'static str = "/etc/sudoers.toml";
+
+/// Matches any user or command in a rule.
+pub const ALL: &'static str = "ALL";
+
+/// Lets the caller choose the directory of a command, see [`Rule::chdir`].
+pub const ANY_DIR: &'static str = "*";
+
+/// Pseudo command allowing to edit the files following it, see `sudo -e`.
+pub const SUDOEDIT: &'static str = "sudoedit";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    /// The policy file may be modified by someone other than root.
+    Insecure,
+    /// The policy is well-formed but has a meaningless entry.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse(err) => write!(f, "{}", err),
+            Error::Insecure => write!(f, "must be owned by root and not writable by others"),
+            Error::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Why a command could not be resolved, see [`Defaults::resolve_command`].
+#[derive(Debug)]
+pub enum ResolveError {
+    NotFound,
+    /// The command is a relative path, which the policy does not allow.
+    Relative,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sudoers {
+    #[serde(default)]
+    pub defaults: Defaults,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    /// Minutes for which a successful authentication is remembered.
+    pub timestamp_timeout: u64,
+    /// File authentication attempts are appended to.
+    pub audit_log: String,
+    /// Only keep the variables of `env_keep` from the caller's environment.
+    pub env_reset: bool,
+    /// Variables kept if `env_reset` is set.
+    pub env_keep: Vec<String>,
+    /// Variables removed if `env_reset` is not set.
+    pub env_delete: Vec<String>,
+    /// `PATH` of the commands run, also used to find them.
+    pub secure_path: String,
+    /// Allow commands given as a path relative to the current directory.
+    pub allow_relative_paths: bool,
+    /// Record the terminal sessions of every command.
+    pub log_io: bool,
+    /// Directory the recorded sessions are stored in.
+    pub iolog_dir: String,
+}
+
+impl Default for Defaults {
+    fn default() -> Defaults {
+        let strings = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        Defaults {
+            timestamp_timeout: 5,
+            audit_log: "/scheme/log".to_string(),
+            env_reset: true,
+            env_keep: strings(&[
+                "TERM", "TTY", "COLUMNS", "LINES", "LANG", "LC_*", "TZ", "DISPLAY",
+            ]),
+            env_delete: strings(&[
+                "LD_*", "IFS", "ENV", "BASH_ENV", "PS4", "PERL*", "PYTHON*", "RUST*", "CARGO*",
+            ]),
+            secure_path: "/usr/bin".to_string(),
+            allow_relative_paths: false,
+            log_io: false,
+            iolog_dir: crate::iolog::IOLOG_DIR.to_string(),
+        }
+    }
+}
+
+impl Defaults {
+    /// How long a successful authentication is remembered, see
+    /// `timestamp_timeout`.
+    pub fn timestamp_duration(&self) -> Duration {
+        Duration::from_secs(self.timestamp_timeout.saturating_mul(60))
+    }
+
+    /// Check whether the variable `name` of the caller's environment is
+    /// passed to the command. `preserve` lists the variables the caller asked
+    /// to keep, all of them if it is empty.
+    pub fn keeps_var(&self, name: &str, preserve: Option<&[String]>) -> bool {
+        if name == "PATH" && !self.secure_path.is_empty() {
+            return false;
+        }
+
+        match preserve {
+            Some([]) => true,
+            Some(names) if names.iter().any(|preserved| preserved == name) => true,
+            _ if self.env_reset => matches_any(&self.env_keep, name),
+            _ => !matches_any(&self.env_delete, name),
+        }
+    }
+
+    /// Resolve the executable `cmd`, given by a caller whose current
+    /// directory is `cwd`, to an absolute path. Names are searched in the
+    /// absolute directories of `secure_path`, never in the `PATH` of the
+    /// caller, so none are found if it is empty.
+    pub fn resolve_command(&self, cmd: &str, cwd: &str) -> Result<String, ResolveError> {
+        let is_executable = |path: &Path| {
+            fs::metadata(path)
+                .is_ok_and(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+        };
+
+        let path = if cmd.starts_with('/') {
+            PathBuf::from(cmd)
+        } else if cmd.contains('/') {
+            if !self.allow_relative_paths {
+                return Err(ResolveError::Relative);
+            }
+            // Collecting the components drops the `.` of `./cmd`
+            Path::new(cwd).join(cmd).components().collect()
+        } else {
+            env::split_paths(&self.secure_path)
+                .filter(|dir| dir.is_absolute())
+                .map(|dir| dir.join(cmd))
+                .find(|path| is_executable(path))
+                .ok_or(ResolveError::NotFound)?
+        };
+        if !is_executable(&path) {
+            return Err(ResolveError::NotFound);
+        }
+        path.into_os_string()
+            .into_string()
+            .map_err(|_| ResolveError::NotFound)
+    }
+}
+
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Login names this rule applies to.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Groups whose members this rule applies to.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Users that commands may be run as.
+    #[serde(default = "default_runas")]
+    pub runas: Vec<String>,
+    /// Groups that may be selected as primary group of the command.
+    #[serde(default)]
+    pub runas_groups: Vec<String>,
+    /// Commands that may be run.
+    pub commands: Vec<String>,
+    /// Run the commands without asking for the user's password.
+    #[serde(default)]
+    pub nopasswd: bool,
+    /// Allow the caller to keep its environment, see `sudo -E`.
+    #[serde(default)]
+    pub setenv: bool,
+    /// Schemes in the namespace of the commands, every scheme if not set.
+    #[serde(default)]
+    pub schemes: Option<Vec<String>>,
+    /// Seconds after which the commands are killed.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Directory the commands run in, [`ANY_DIR`] letting the caller choose
+    /// it with `sudo --chdir`.
+    #[serde(default)]
+    pub chdir: Option<String>,
+    /// File mode creation mask of the commands.
+    #[serde(default)]
+    pub umask: Option<u32>,
+    /// Record the terminal sessions of the commands.
+    #[serde(default)]
+    pub log_io: bool,
+}
+
+fn default_runas() -> Vec<String> {
+    vec!["root".to_string()]
+}
+
+impl Default for Sudoers {
+    fn default() -> Sudoers {
+        Sudoers {
+            defaults: Defaults::default(),
+            rules: vec![Rule {
+                users: Vec::new(),
+                groups: vec!["sudo".to_string()],
+                runas: vec![ALL.to_string()],
+                runas_groups: vec![ALL.to_string()],
+                commands: vec![ALL.to_string()],
+                nopasswd: false,
+                setenv: false,
+                schemes: None,
+                timeout: None,
+                chdir: None,
+                umask: None,
+                log_io: false,
+            }],
+        }
+    }
+}
+
+impl Sudoers {
+    /// Read the policy from [`SUDOERS_FILE`], falling back to the default
+    /// policy if the file does not exist.
+    pub fn load() -> Result<Sudoers, Error> {
+        let metadata = match fs::metadata(SUDOERS_FILE) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Sudoers::default()),
+            Err(err) => return Err(Error::Io(err)),
+        };
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            return Err(Error::Insecure);
+        }
+
+        let config_str = fs::read_to_string(SUDOERS_FILE).map_err(Error::Io)?;
+        Sudoers::parse(&config_str)
+    }
+
+    pub fn parse(config_str: &str) -> Result<Sudoers, Error> {
+        let sudoers: Sudoers = toml::from_str(config_str).map_err(Error::Parse)?;
+        for rule in &sudoers.rules {
+            rule.check()?;
+        }
+        Ok(sudoers)
+    }
+
+    /// The rules applying to `user`, a member of `groups`.
+    pub fn rules_for(&self, user: &str, groups: &[String]) -> impl Iterator<Item = &Rule> {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.applies_to(user, groups))
+    }
+
+    /// Find the first rule allowing `user` to run `command` as `target`,
+    /// optionally with `target_group` as primary group.
+    ///
+    /// If `command` is `None` the command is not known, so only rules
+    /// allowing every command are considered.
+    pub fn find_rule(
+        &self,
+        user: &str,
+        groups: &[String],
+        target: &str,
+        target_group: Option<&str>,
+        command: Option<&[String]>,
+    ) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                rule.applies_to(user, groups) && rule.allows_target(target, target_group)
+            })
+            .find(|rule| match command {
+                Some(argv) => rule.allows_command(argv),
+                None => rule.commands.iter().any(|cmd| cmd == ALL),
+            })
+    }
+}
+
+/// Formats a rule the way `sudo -l` lists it, like
+/// `(root, www : www) NOPASSWD: /usr/bin/netctl restart, /usr/bin/dmesg`.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}", self.runas.join(", "))?;
+        if !self.runas_groups.is_empty() {
+            write!(f, " : {}", self.runas_groups.join(", "))?;
+        }
+        write!(f, ") ")?;
+        if self.nopasswd {
+            write!(f, "NOPASSWD: ")?;
+        }
+        if self.setenv {
+            write!(f, "SETENV: ")?;
+        }
+        if let Some(schemes) = &self.schemes {
+            write!(f, "SCHEMES={}: ", schemes.join(","))?;
+        }
+        if let Some(timeout) = self.timeout {
+            write!(f, "TIMEOUT={}: ", timeout)?;
+        }
+        if let Some(chdir) = &self.chdir {
+            write!(f, "CHDIR={}: ", chdir)?;
+        }
+        if let Some(umask) = self.umask {
+            write!(f, "UMASK={:04o}: ", umask)?;
+        }
+        if self.log_io {
+            write!(f, "LOG_IO: ")?;
+        }
+        write!(f, "{}", self.commands.join(", "))
+    }
+}
+
+impl Rule {
+    pub fn applies_to(&self, user: &str, groups: &[String]) -> bool {
+        self.users.iter().any(|name| name == ALL || name == user)
+            || self
+                .groups
+                .iter()
+                .any(|group| groups.iter().any(|name| name == group))
+    }
+
+    pub fn allows_target(&self, target: &str, target_group: Option<&str>) -> bool {
+        self.runas.iter().any(|name| name == ALL || name == target)
+            && target_group.is_none_or(|group| {
+                self.runas_groups
+                    .iter()
+                    .any(|name| name == ALL || name == group)
+            })
+    }
+
+    /// Refuse `sudoedit` commands which do not list the absolute paths of
+    /// the files they allow to edit, as they would allow editing any file.
+    fn check(&self) -> Result<(), Error> {
+        for cmd in &self.commands {
+            let mut parts = cmd.split_whitespace();
+            if parts.next() != Some(SUDOEDIT) {
+                continue;
+            }
+            let files: Vec<&str> = parts.collect();
+            if files.is_empty() || !files.iter().all(|file| file.starts_with('/')) {
+                return Err(Error::Invalid(format!(
+                    "\"{}\" must list the absolute paths of the files to edit",
+                    cmd
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check `argv`, whose first element is the absolute path of the
+    /// executable, against the commands of this rule.
+    pub fn allows_command(&self, argv: &[String]) -> bool {
+        let Some((path, args)) = argv.split_first() else {
+            return false;
+        };
+
+        self.commands.iter().any(|cmd| {
+            if cmd == ALL {
+                return true;
+            }
+
+            let mut parts = cmd.split_whitespace();
+            if parts.next() != Some(path.as_str()) {
+                return false;
+            }
+            let allowed_args: Vec<&str> = parts.collect();
+            allowed_args.is_empty()
+                || allowed_args
+                    .iter()
+                    .copied()
+                    .eq(args.iter().map(String::as_str))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    const POLICY: &str = r#"
+        [defaults]
+        env_keep = ["TERM", "LC_*"]
+        secure_path = ""
+
+        [[rule]]
+        users = ["alice"]
+        commands = ["/usr/bin/netctl restart", "/usr/bin/dmesg"]
+        nopasswd = true
+
+        [[rule]]
+        users = ["alice"]
+        runas = ["www"]
+        runas_groups = ["www", "log"]
+        commands = ["/usr/bin/apachectl"]
+
+        [[rule]]
+        groups = ["wheel"]
+        runas = ["ALL"]
+        runas_groups = ["ALL"]
+        commands = ["ALL"]
+
+        [[rule]]
+        users = ["ALL"]
+        commands = ["/usr/bin/id"]
+    "#;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn sudoers() -> Sudoers {
+        Sudoers::parse(POLICY).unwrap()
+    }
+
+    #[test]
+    fn parse_defaults() {
+        let sudoers = Sudoers::parse("").unwrap();
+        assert!(sudoers.rules.is_empty());
+        assert_eq!(sudoers.defaults.timestamp_timeout, 5);
+        assert_eq!(
+            sudoers.defaults.timestamp_duration(),
+            Duration::from_secs(5 * 60)
+        );
+        assert!(sudoers.defaults.env_reset);
+
+        let sudoers = Sudoers::parse("[[rule]]\nusers = [\"bob\"]\ncommands = [\"ALL\"]").unwrap();
+        assert_eq!(sudoers.rules[0].runas, ["root"]);
+        assert!(sudoers.rules[0].runas_groups.is_empty());
+        assert!(!sudoers.rules[0].nopasswd);
+    }
+
+    #[test]
+    fn parse_rejects_unknown_fields() {
+        assert!(matches!(
+            Sudoers::parse("[[rule]]\nusers = [\"bob\"]\ncommands = []\nnopassword = true"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            Sudoers::parse("[[rule]]\nusers = [\"bob\"]"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_sudoedit_without_files() {
+        let rule =
+            |command: &str| format!("[[rule]]\nusers = [\"bob\"]\ncommands = [\"{}\"]", command);
+        assert!(Sudoers::parse(&rule("sudoedit /etc/hosts /etc/motd")).is_ok());
+        assert!(matches!(
+            Sudoers::parse(&rule("sudoedit")),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            Sudoers::parse(&rule("sudoedit hosts")),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let sudoers = sudoers();
+
+        let rule = sudoers
+            .find_rule("alice", &[], "root", None, Some(&argv(&["/usr/bin/dmesg"])))
+            .unwrap();
+        assert!(rule.nopasswd);
+
+        // The rule of the wheel group also allows it, but comes later
+        let wheel = argv(&["wheel"]);
+        let rule = sudoers
+            .find_rule(
+                "alice",
+                &wheel,
+                "root",
+                None,
+                Some(&argv(&["/usr/bin/dmesg"])),
+            )
+            .unwrap();
+        assert!(rule.nopasswd);
+
+        let rule = sudoers
+            .find_rule(
+                "alice",
+                &wheel,
+                "root",
+                None,
+                Some(&argv(&["/usr/bin/reboot"])),
+            )
+            .unwrap();
+        assert_eq!(rule.groups, ["wheel"]);
+
+        let rule = sudoers
+            .find_rule("bob", &[], "root", None, Some(&argv(&["/usr/bin/id"])))
+            .unwrap();
+        assert_eq!(rule.users, ["ALL"]);
+        assert!(
+            sudoers
+                .find_rule("bob", &[], "root", None, Some(&argv(&["/usr/bin/dmesg"])))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn unknown_command_needs_all() {
+        let sudoers = sudoers();
+        assert!(
+            sudoers
+                .find_rule("alice", &[], "root", None, None)
+                .is_none()
+        );
+        assert!(
+            sudoers
+                .find_rule("bob", &argv(&["wheel"]), "root", None, None)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn rules_for_user() {
+        let sudoers = sudoers();
+        assert_eq!(sudoers.rules_for("alice", &[]).count(), 3);
+        assert_eq!(sudoers.rules_for("bob", &argv(&["wheel"])).count(), 2);
+        assert_eq!(sudoers.rules_for("bob", &[]).count(), 1);
+    }
+
+    #[test]
+    fn runas_users_and_groups() {
+        let sudoers = sudoers();
+        let rule = &sudoers.rules[1];
+        assert!(rule.allows_target("www", None));
+        assert!(rule.allows_target("www", Some("log")));
+        assert!(!rule.allows_target("www", Some("wheel")));
+        assert!(!rule.allows_target("root", None));
+
+        // No group may be requested unless `runas_groups` lists it
+        let rule = &sudoers.rules[0];
+        assert!(rule.allows_target("root", None));
+        assert!(!rule.allows_target("root", Some("root")));
+
+        let rule = &sudoers.rules[2];
+        assert!(rule.allows_target("anyone", Some("anything")));
+
+        let apachectl = argv(&["/usr/bin/apachectl"]);
+        assert!(
+            sudoers
+                .find_rule("alice", &[], "www", Some("www"), Some(&apachectl))
+                .is_some()
+        );
+        assert!(
+            sudoers
+                .find_rule("alice", &[], "root", None, Some(&apachectl))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn command_arguments() {
+        let rule = &sudoers().rules[0];
+        assert!(rule.allows_command(&argv(&["/usr/bin/netctl", "restart"])));
+        assert!(!rule.allows_command(&argv(&["/usr/bin/netctl"])));
+        assert!(!rule.allows_command(&argv(&["/usr/bin/netctl", "stop"])));
+        assert!(!rule.allows_command(&argv(&["/usr/bin/netctl", "restart", "eth0"])));
+
+        // A path without arguments allows any arguments
+        assert!(rule.allows_command(&argv(&["/usr/bin/dmesg"])));
+        assert!(rule.allows_command(&argv(&["/usr/bin/dmesg", "-c"])));
+
+        // Paths are matched exactly
+        assert!(!rule.allows_command(&argv(&["/bin/dmesg"])));
+        assert!(!rule.allows_command(&argv(&["dmesg"])));
+        assert!(!rule.allows_command(&[]));
+    }
+
+    #[test]
+    fn all_commands() {
+        let rule = &sudoers().rules[2];
+        assert!(rule.allows_command(&argv(&["/usr/bin/reboot"])));
+        assert!(rule.allows_command(&argv(&["/usr/bin/netctl", "stop"])));
+        assert!(!rule.allows_command(&[]));
+    }
+
+    #[test]
+    fn environment() {
+        let defaults = sudoers().defaults;
+        assert!(defaults.keeps_var("TERM", None));
+        assert!(defaults.keeps_var("LC_ALL", None));
+        assert!(!defaults.keeps_var("HOME", None));
+        assert!(defaults.keeps_var("HOME", Some(&argv(&["HOME"]))));
+        assert!(!defaults.keeps_var("EDITOR", Some(&argv(&["HOME"]))));
+        assert!(defaults.keeps_var("EDITOR", Some(&[])));
+        // `PATH` is only kept without a secure path
+        assert!(defaults.keeps_var("PATH", Some(&[])));
+
+        let defaults = Defaults {
+            env_reset: false,
+            ..Defaults::default()
+        };
+        assert!(defaults.keeps_var("HOME", None));
+        assert!(!defaults.keeps_var("LD_PRELOAD", None));
+        assert!(!defaults.keeps_var("PATH", Some(&[])));
+    }
+
+    #[test]
+    fn resolve_commands() {
+        let dir = env::temp_dir().join(format!("sudoers-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let executable = dir.join("tool");
+        fs::write(&executable, "").unwrap();
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.join("data"), "").unwrap();
+
+        let dir_str = dir.to_str().unwrap();
+        let tool = executable.to_str().unwrap();
+        let mut defaults = Defaults {
+            secure_path: dir_str.to_string(),
+            ..Defaults::default()
+        };
+        assert_eq!(defaults.resolve_command("tool", "/").unwrap(), tool);
+        assert_eq!(defaults.resolve_command(tool, "/").unwrap(), tool);
+        assert!(matches!(
+            defaults.resolve_command("data", "/"),
+            Err(ResolveError::NotFound)
+        ));
+        assert!(matches!(
+            defaults.resolve_command("missing", "/"),
+            Err(ResolveError::NotFound)
+        ));
+        assert!(matches!(
+            defaults.resolve_command("./tool", dir_str),
+            Err(ResolveError::Relative)
+        ));
+
+        defaults.allow_relative_paths = true;
+        assert_eq!(defaults.resolve_command("./tool", dir_str).unwrap(), tool);
+
+        // Not searched in the current directory of the daemon
+        env::set_current_dir(&dir).unwrap();
+        for secure_path in ["", ".", ":"] {
+            defaults.secure_path = secure_path.to_string();
+            assert!(matches!(
+                defaults.resolve_command("tool", dir_str),
+                Err(ResolveError::NotFound)
+            ));
+        }
+        env::set_current_dir("/").unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! Back-off of failed authentications through the sudo daemon, which checks
+//! the passwords of `sudo`, `su` and `passwd`.
+
+use std::collections::HashMap;
+use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use redox_users::Config;
+use syscall::error::{EAGAIN, Error, Result};
+
+/// Delay after the first failed authentication of a user, doubled by every
+/// further failure.
+pub const FAILURE_DELAY: Duration = Duration::from_secs(1);
+/// Failed authentications after which a user is locked out.
+pub const LOCKOUT_FAILURES: u32 = 8;
+pub const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Time a user has to wait after `count` consecutive failed authentications.
+fn failure_delay(count: u32) -> Duration {
+    if count >= LOCKOUT_FAILURES {
+        LOCKOUT_DURATION
+    } else {
+        FAILURE_DELAY * 2u32.pow(count - 1)
+    }
+}
+
+/// Failed authentications of a user through sudo, su or passwd.
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    /// Time left until the user may try to authenticate again.
+    fn remaining(&self) -> Duration {
+        failure_delay(self.count).saturating_sub(self.last.elapsed())
+    }
+}
+
+/// Slows down password guessing by making users wait longer after every
+/// failed authentication, up to a temporary lockout.
+///
+/// Attempts made too early are rejected with `EAGAIN` instead of sleeping,
+/// as that would block the daemon for every user. The caller is told how
+/// long to wait, see [`wait_to_retry`]. Users who did not fail for
+/// [`LOCKOUT_DURATION`] start over.
+#[derive(Default)]
+pub struct Throttle {
+    failures: HashMap<u32, Failures>,
+}
+
+impl Throttle {
+    pub fn check(&self, uid: u32) -> Result<()> {
+        if self.remaining(uid).is_zero() {
+            Ok(())
+        } else {
+            Err(Error::new(EAGAIN))
+        }
+    }
+
+    /// Time left until the user `uid` may try to authenticate again.
+    pub fn remaining(&self, uid: u32) -> Duration {
+        self.failures
+            .get(&uid)
+            .map_or(Duration::ZERO, Failures::remaining)
+    }
+
+    pub fn record(&mut self, uid: u32, verified: bool) {
+        if verified {
+            self.failures.remove(&uid);
+            return;
+        }
+
+        // Dropped here, they would pile up otherwise
+        self.failures
+            .retain(|_, failures| failures.last.elapsed() < LOCKOUT_DURATION);
+
+        let failures = self.failures.entry(uid).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        if failures.count >= LOCKOUT_FAILURES {
+            // The lockout expired, start over
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = Instant::now();
+    }
+}
+
+/// The time left until the caller of the `su` or `passwd` handle `fd` of
+/// the daemon may try again, after a password it wrote was refused with
+/// `EAGAIN`. Reading the handle gives it in milliseconds.
+pub fn retry_delay(fd: usize) -> Duration {
+    let mut buf = [0; 20];
+    let millis = libredox::call::read(fd, &mut buf)
+        .ok()
+        .and_then(|count| str::from_utf8(&buf[..count]).ok()?.parse().ok())
+        .unwrap_or(0);
+    Duration::from_millis(millis)
+}
+
+/// Tell the user of `program` that they have to wait `delay` before their
+/// password is checked, and wait.
+pub fn wait_to_retry(program: &str, delay: Duration) {
+    // Unknown, or run out since the daemon refused the password
+    let delay = if delay.is_zero() {
+        FAILURE_DELAY
+    } else {
+        delay
+    };
+    let seconds = delay.as_millis().div_ceil(1000);
+    eprintln!(
+        "{}: too many failed attempts, waiting {} seconds",
+        program, seconds
+    );
+    thread::sleep(delay);
+}
+
+/// Configuration used to verify passwords. Failed attempts are delayed by
+/// [`Throttle`] rather than by redox_users, which sleeps.
+pub fn auth_config() -> Config {
+    Config::default().auth_delay(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_lockout() {
+        assert_eq!(failure_delay(1), FAILURE_DELAY);
+        assert_eq!(failure_delay(2), FAILURE_DELAY * 2);
+        assert_eq!(failure_delay(LOCKOUT_FAILURES - 1), FAILURE_DELAY * 64);
+        assert_eq!(failure_delay(LOCKOUT_FAILURES), LOCKOUT_DURATION);
+    }
+
+    #[test]
+    fn failures_are_throttled_per_user() {
+        let mut throttle = Throttle::default();
+        assert!(throttle.check(1000).is_ok());
+
+        throttle.record(1000, false);
+        assert_eq!(throttle.check(1000).unwrap_err().errno, EAGAIN);
+        assert!(throttle.remaining(1000) > Duration::ZERO);
+        assert!(throttle.remaining(1000) <= FAILURE_DELAY);
+        assert!(throttle.check(1001).is_ok());
+        assert_eq!(throttle.remaining(1001), Duration::ZERO);
+
+        throttle.record(1000, true);
+        assert!(throttle.check(1000).is_ok());
+    }
+
+    #[test]
+    fn failures_start_over_after_lockout() {
+        let mut throttle = Throttle::default();
+        for _ in 0..LOCKOUT_FAILURES {
+            throttle.record(1000, false);
+        }
+        assert!(throttle.failures[&1000].remaining() > FAILURE_DELAY * 64);
+
+        throttle.record(1000, false);
+        assert_eq!(throttle.failures[&1000].count, 1);
+    }
+
+    #[test]
+    fn old_failures_are_dropped() {
+        let mut throttle = Throttle::default();
+        throttle.record(1000, false);
+        throttle.record(1000, false);
+        throttle.record(1001, false);
+        let Some(long_ago) = Instant::now().checked_sub(LOCKOUT_DURATION) else {
+            return;
+        };
+        throttle.failures.get_mut(&1000).unwrap().last = long_ago;
+
+        throttle.record(1002, false);
+        assert!(!throttle.failures.contains_key(&1000));
+        assert!(throttle.failures.contains_key(&1001));
+
+        throttle.record(1001, false);
+        assert_eq!(throttle.failures[&1001].count, 2);
+    }
+}